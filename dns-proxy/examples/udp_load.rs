@@ -0,0 +1,130 @@
+// Load generator for the UDP listener: many clients send the same queries (answered
+// from the cache after the first one) and the answers per second are reported.
+//
+//     cargo run --release --example udp_load -- 127.0.0.1:1553 64 10 example.com
+//
+// Arguments: server address, concurrent clients, seconds, names (comma separated)
+
+use std::env;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+fn query(id: u16, name: &str) -> Vec<u8> {
+    // header: ID, RD flag, one question
+    let mut message = id.to_be_bytes().to_vec();
+    message.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    // root label, type A, class IN
+    message.extend_from_slice(&[0, 0, 1, 0, 1]);
+    message
+}
+
+async fn client(
+    server: SocketAddr,
+    names: Arc<Vec<String>>,
+    deadline: Instant,
+    answers: Arc<AtomicU64>,
+    timeouts: Arc<AtomicU64>,
+) {
+    let bind: SocketAddr = if server.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    };
+    let socket = UdpSocket::bind(bind).await.unwrap();
+    socket.connect(server).await.unwrap();
+
+    let mut buffer = [0u8; 4096];
+    let mut id = 0u16;
+    while Instant::now() < deadline {
+        id = id.wrapping_add(1);
+        let name = &names[id as usize % names.len()];
+        socket.send(&query(id, name)).await.unwrap();
+
+        // answers to earlier, timed out queries are skipped
+        let answered = timeout(Duration::from_secs(1), async {
+            loop {
+                let size = socket.recv(&mut buffer).await.unwrap();
+                if size >= 2 && buffer[..2] == id.to_be_bytes() {
+                    return;
+                }
+            }
+        })
+        .await;
+        match answered {
+            Ok(()) => answers.fetch_add(1, Ordering::Relaxed),
+            Err(_) => timeouts.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = env::args().collect();
+    let server: SocketAddr = args
+        .get(1)
+        .map(|a| a.parse().expect("Invalid server address"))
+        .unwrap_or_else(|| "127.0.0.1:53".parse().unwrap());
+    let clients: usize = args.get(2).map_or(64, |a| a.parse().unwrap());
+    let seconds: u64 = args.get(3).map_or(10, |a| a.parse().unwrap());
+    let names: Vec<String> = args
+        .get(4)
+        .map_or("example.com", |a| a.as_str())
+        .split(',')
+        .map(String::from)
+        .collect();
+
+    // fills the cache, so the upstream is not measured
+    let warm_up = Instant::now() + Duration::from_secs(1);
+    let names = Arc::new(names);
+    let (answers, timeouts) = (Arc::new(AtomicU64::new(0)), Arc::new(AtomicU64::new(0)));
+    client(
+        server,
+        names.clone(),
+        warm_up,
+        answers.clone(),
+        timeouts.clone(),
+    )
+    .await;
+    answers.store(0, Ordering::Relaxed);
+    timeouts.store(0, Ordering::Relaxed);
+
+    let start = Instant::now();
+    let deadline = start + Duration::from_secs(seconds);
+    let tasks: Vec<_> = (0..clients)
+        .map(|_| {
+            tokio::spawn(client(
+                server,
+                names.clone(),
+                deadline,
+                answers.clone(),
+                timeouts.clone(),
+            ))
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+
+    // results only compare between runs on the same number of cores
+    let cores = thread::available_parallelism().map_or(1, |n| n.get());
+    let elapsed = start.elapsed().as_secs_f64();
+    let answers = answers.load(Ordering::Relaxed);
+    println!(
+        "{} cores, {} clients, {:.1}s: {} answers ({:.0}/s), {} timeouts",
+        cores,
+        clients,
+        elapsed,
+        answers,
+        answers as f64 / elapsed,
+        timeouts.load(Ordering::Relaxed)
+    );
+}
@@ -0,0 +1,264 @@
+use socket2::{Domain, Socket, Type};
+use std::collections::HashSet;
+use std::env;
+use std::io;
+use std::mem::ManuallyDrop;
+use std::os::unix::io::{FromRawFd, RawFd};
+use tokio::net::{TcpListener, UdpSocket, UnixDatagram, UnixListener};
+
+use crate::config::invalid;
+
+// Sockets bound by someone else (systemd or a supervisor) and passed as open file
+// descriptors, so the proxy can serve port 53 without the privilege to bind it
+
+// https://www.freedesktop.org/software/systemd/man/latest/sd_listen_fds.html
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+#[derive(Default)]
+pub struct InheritedSockets {
+    pub tcp: Vec<TcpListener>,
+    pub udp: Vec<UdpSocket>,
+    pub dot: Vec<TcpListener>,
+    pub doh: Vec<TcpListener>,
+    pub doq: Vec<UdpSocket>,
+    pub unix_stream: Vec<UnixListener>,
+    pub unix_datagram: Vec<UnixDatagram>,
+}
+
+// The descriptors passed in the environment, with their names
+pub struct PassedFds {
+    fds: Vec<(RawFd, String)>,
+    passed: bool,
+}
+
+impl PassedFds {
+    // Reads the variables and unsets them, like sd_listen_fds(1), so the processes
+    // started by the proxy (e.g. a health check) do not take these descriptors for
+    // theirs. Changing the environment races with any other thread reading it: this
+    // must run before the runtime (or anything else) starts threads.
+    pub fn take_from_env() -> io::Result<Self> {
+        let systemd = systemd_fds(
+            env::var("LISTEN_PID").ok().as_deref(),
+            env::var("LISTEN_FDS").ok().as_deref(),
+            env::var("LISTEN_FDNAMES").ok().as_deref(),
+            std::process::id(),
+        )?;
+        let tcp = parse_fds("TCP_FDS", env::var("TCP_FDS").ok().as_deref())?;
+        let udp = parse_fds("UDP_FDS", env::var("UDP_FDS").ok().as_deref())?;
+        let passed =
+            systemd.is_some() || env::var("TCP_FDS").is_ok() || env::var("UDP_FDS").is_ok();
+
+        for name in [
+            "LISTEN_PID",
+            "LISTEN_FDS",
+            "LISTEN_FDNAMES",
+            "TCP_FDS",
+            "UDP_FDS",
+        ] {
+            env::remove_var(name);
+        }
+
+        let fds = systemd
+            .unwrap_or_default()
+            .into_iter()
+            .chain(tcp.into_iter().map(|fd| (fd, String::from("tcp"))))
+            .chain(udp.into_iter().map(|fd| (fd, String::from("udp"))))
+            .collect();
+        Ok(PassedFds { fds, passed })
+    }
+
+    // True when sockets are passed: the default listen address is then not bound
+    pub fn passes_sockets(&self) -> bool {
+        self.passed
+    }
+}
+
+// LISTEN_FDS descriptors start at 3 and are only meant for the process in LISTEN_PID:
+// None when they are not for this one. Their names (FileDescriptorName= in the .socket
+// unit) may select "dot", "doh" or "doq".
+pub fn systemd_fds(
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    listen_fdnames: Option<&str>,
+    pid: u32,
+) -> io::Result<Option<Vec<(RawFd, String)>>> {
+    let count = match (listen_pid, listen_fds) {
+        (Some(listen_pid), Some(listen_fds)) if listen_pid.trim() == pid.to_string() => {
+            match listen_fds.trim().parse::<RawFd>() {
+                Ok(count) if count >= 0 => count,
+                _ => return Err(invalid(format!("Invalid LISTEN_FDS: {}", listen_fds))),
+            }
+        }
+        _ => return Ok(None),
+    };
+
+    let names: Vec<&str> = listen_fdnames
+        .map(|names| names.split(':').collect())
+        .unwrap_or_default();
+    Ok(Some(
+        (0..count)
+            .map(|i| {
+                let name = names.get(i as usize).copied().unwrap_or("");
+                (SD_LISTEN_FDS_START + i, String::from(name))
+            })
+            .collect(),
+    ))
+}
+
+// Comma separated descriptor numbers, e.g. TCP_FDS=3,4
+pub fn parse_fds(name: &str, value: Option<&str>) -> io::Result<Vec<RawFd>> {
+    value
+        .unwrap_or("")
+        .split(',')
+        .map(str::trim)
+        .filter(|fd| !fd.is_empty())
+        .map(|fd| match fd.parse::<RawFd>() {
+            Ok(fd) if fd >= 0 => Ok(fd),
+            _ => Err(invalid(format!(
+                "Invalid file descriptor in {}: {}",
+                name, fd
+            ))),
+        })
+        .collect()
+}
+
+impl InheritedSockets {
+    // Within the runtime, which the sockets are registered with
+    pub fn from_fds(passed: PassedFds) -> io::Result<Self> {
+        let mut sockets = InheritedSockets::default();
+        let mut seen = HashSet::new();
+        for (fd, name) in passed.fds {
+            // owning the same descriptor twice would close it twice
+            if !seen.insert(fd) {
+                return Err(invalid(format!("File descriptor {} passed twice", fd)));
+            }
+            sockets.add(fd, &name)?;
+        }
+        Ok(sockets)
+    }
+
+    // Takes ownership of the descriptor, sorted by its socket type unless the name
+    // asks for a protocol ("dot", "doh", "doq", or "tcp" and "udp" to be explicit).
+    // Other names, e.g. the unit name systemd uses without FileDescriptorName=, are
+    // ignored.
+    fn add(&mut self, fd: RawFd, name: &str) -> io::Result<()> {
+        let name = match name {
+            "tcp" | "udp" | "dot" | "doh" | "doq" => name,
+            _ => "",
+        };
+        // probe without owning it first: dropping a descriptor that is not open is an error
+        // SAFETY: the socket is never dropped, so the descriptor is left as it is
+        // whether it is open or not, and only read by getsockopt
+        let probe = ManuallyDrop::new(unsafe { Socket::from_raw_fd(fd) });
+        let (domain, ty) = match (probe.domain(), probe.r#type()) {
+            (Ok(domain), Ok(ty)) => (domain, ty),
+            (Err(e), _) | (_, Err(e)) if e.raw_os_error() == Some(libc::EBADF) => {
+                return Err(invalid(format!("File descriptor {} is not open", fd)))
+            }
+            _ => return Err(invalid(format!("File descriptor {} is not a socket", fd))),
+        };
+
+        // SAFETY: the descriptor was passed to this process for it to use, and is owned
+        // only once (see from_fds)
+        let socket = ManuallyDrop::into_inner(probe);
+        socket.set_nonblocking(true)?;
+        socket.set_cloexec(true)?;
+
+        let inet = domain == Domain::IPV4 || domain == Domain::IPV6;
+        match (name, inet, ty) {
+            ("" | "tcp", true, Type::STREAM) => {
+                self.tcp.push(TcpListener::from_std(socket.into())?)
+            }
+            ("" | "udp", true, Type::DGRAM) => self.udp.push(UdpSocket::from_std(socket.into())?),
+            ("dot", true, Type::STREAM) => self.dot.push(TcpListener::from_std(socket.into())?),
+            ("doh", true, Type::STREAM) => self.doh.push(TcpListener::from_std(socket.into())?),
+            ("doq", true, Type::DGRAM) => self.doq.push(UdpSocket::from_std(socket.into())?),
+            ("" | "tcp", false, Type::STREAM) if domain == Domain::UNIX => self
+                .unix_stream
+                .push(UnixListener::from_std(socket.into())?),
+            ("" | "udp", false, Type::DGRAM) if domain == Domain::UNIX => self
+                .unix_datagram
+                .push(UnixDatagram::from_std(socket.into())?),
+            _ => {
+                return Err(invalid(format!(
+                    "File descriptor {} is not a suitable socket for \"{}\"",
+                    fd, name
+                )))
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::activation::{parse_fds, systemd_fds, InheritedSockets};
+    use std::os::unix::io::{AsRawFd, IntoRawFd};
+
+    #[test]
+    fn test_systemd_fds() {
+        let fds = systemd_fds(Some("42"), Some("3"), Some("dns:dot:doq"), 42)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            fds,
+            vec![
+                (3, String::from("dns")),
+                (4, String::from("dot")),
+                (5, String::from("doq"))
+            ]
+        );
+
+        // sockets meant for another process, e.g. inherited by a child
+        assert!(systemd_fds(Some("41"), Some("3"), None, 42)
+            .unwrap()
+            .is_none());
+        assert!(systemd_fds(None, Some("3"), None, 42).unwrap().is_none());
+        assert!(systemd_fds(None, None, None, 42).unwrap().is_none());
+        assert_eq!(
+            systemd_fds(Some("42"), Some("1"), None, 42)
+                .unwrap()
+                .unwrap()[0]
+                .1,
+            ""
+        );
+        assert!(systemd_fds(Some("42"), Some("x"), None, 42).is_err());
+    }
+
+    #[test]
+    fn test_parse_fds() {
+        assert_eq!(parse_fds("TCP_FDS", Some("3, 4")).unwrap(), vec![3, 4]);
+        assert!(parse_fds("TCP_FDS", None).unwrap().is_empty());
+        assert!(parse_fds("TCP_FDS", Some("3,-1")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_add_sockets() {
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = tcp.local_addr().unwrap();
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let doq = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let mut sockets = InheritedSockets::default();
+        sockets.add(tcp.into_raw_fd(), "").unwrap();
+        sockets.add(udp.into_raw_fd(), "udp").unwrap();
+        sockets.add(doq.into_raw_fd(), "doq").unwrap();
+        assert_eq!(sockets.tcp[0].local_addr().unwrap(), address);
+        assert_eq!(sockets.udp.len(), 1);
+        assert_eq!(sockets.doq.len(), 1);
+
+        // a datagram socket cannot serve TCP
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(sockets.add(udp.into_raw_fd(), "tcp").is_err());
+
+        // the unit name, without FileDescriptorName=
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        sockets.add(tcp.into_raw_fd(), "dns-proxy.socket").unwrap();
+        sockets.add(udp.into_raw_fd(), "dns-proxy.socket").unwrap();
+        assert_eq!((sockets.tcp.len(), sockets.udp.len()), (2, 2));
+
+        let file = std::fs::File::open("/dev/null").unwrap();
+        assert!(sockets.add(file.as_raw_fd(), "").is_err());
+    }
+}
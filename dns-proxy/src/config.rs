@@ -0,0 +1,407 @@
+use std::env;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::dns::dname::DomainName;
+use crate::network::Network;
+use crate::resolver::Strategy;
+use crate::router::Route;
+use crate::tls::TlsSettings;
+use crate::upstream::{Timeouts, UpstreamSettings};
+
+// Everything is read from environment variables to keep the sidecar easy to configure
+pub struct Config {
+    pub upstreams: Vec<UpstreamSettings>,
+    pub routes: Vec<Route>,
+    pub upstream_strategy: Strategy,
+    pub hedge_delay: Option<Duration>,
+    pub health_check_interval: Duration,
+    pub upstream_timeouts: Timeouts,
+    pub upstream_retries: u32,
+    pub udp_addresses: Vec<SocketAddr>,
+    pub tcp_addresses: Vec<SocketAddr>,
+    pub udp_max_in_flight: usize,
+    pub udp_sockets: usize,
+    pub tcp_idle_timeout: Duration,
+    pub dot_addresses: Vec<SocketAddr>,
+    pub doq_addresses: Vec<SocketAddr>,
+    pub doh_addresses: Vec<SocketAddr>,
+    pub doh_path: String,
+    pub doh_plaintext: bool,
+    pub tls: Option<TlsSettings>,
+    pub unix_stream_path: Option<PathBuf>,
+    pub unix_datagram_path: Option<PathBuf>,
+    pub unix_socket_mode: u32,
+    pub proxy_protocol_trusted: Vec<Network>,
+    pub shutdown_grace_period: Duration,
+}
+
+impl Config {
+    // `passes_sockets`: sockets are passed by systemd or a supervisor (see activation)
+    pub fn from_env(passes_sockets: bool) -> io::Result<Self> {
+        let port = parse_number::<u16>("PORT", &var_or("PORT", "53"))?;
+        // with sockets passed, nothing else is bound by default
+        let default_listen = if passes_sockets {
+            String::new()
+        } else {
+            format!("0.0.0.0:{}", port)
+        };
+        let listen = var_or("LISTEN", &default_listen);
+
+        let mut config = Config {
+            upstreams: upstreams()?,
+            routes: parse_routes(&var_or("ROUTES", ""))?,
+            upstream_strategy: Strategy::from_str(&var_or("UPSTREAM_STRATEGY", "order"))
+                .map_err(|e| invalid(format!("Invalid UPSTREAM_STRATEGY: {}", e)))?,
+            hedge_delay: match env::var("HEDGE_DELAY") {
+                Ok(delay) => Some(Duration::from_millis(parse_number("HEDGE_DELAY", &delay)?)),
+                Err(_) => None,
+            },
+            health_check_interval: Duration::from_secs(parse_number(
+                "HEALTH_CHECK_INTERVAL",
+                &var_or("HEALTH_CHECK_INTERVAL", "10"),
+            )?),
+            upstream_timeouts: Timeouts {
+                connect: Duration::from_millis(parse_number(
+                    "UPSTREAM_CONNECT_TIMEOUT",
+                    &var_or("UPSTREAM_CONNECT_TIMEOUT", "2000"),
+                )?),
+                handshake: Duration::from_millis(parse_number(
+                    "UPSTREAM_HANDSHAKE_TIMEOUT",
+                    &var_or("UPSTREAM_HANDSHAKE_TIMEOUT", "3000"),
+                )?),
+                query: Duration::from_millis(parse_number(
+                    "UPSTREAM_QUERY_TIMEOUT",
+                    &var_or("UPSTREAM_QUERY_TIMEOUT", "5000"),
+                )?),
+                idle: Duration::from_secs(parse_number(
+                    "UPSTREAM_IDLE_TIMEOUT",
+                    &var_or("UPSTREAM_IDLE_TIMEOUT", "30"),
+                )?),
+            },
+            upstream_retries: parse_number("UPSTREAM_RETRIES", &var_or("UPSTREAM_RETRIES", "1"))?,
+            udp_addresses: addresses_or("UDP_LISTEN", &listen)?,
+            tcp_addresses: addresses_or("TCP_LISTEN", &listen)?,
+            udp_max_in_flight: parse_number(
+                "UDP_MAX_IN_FLIGHT",
+                &var_or("UDP_MAX_IN_FLIGHT", "1024"),
+            )?,
+            udp_sockets: parse_number("UDP_SOCKETS", &var_or("UDP_SOCKETS", "1"))?,
+            tcp_idle_timeout: Duration::from_secs(parse_number(
+                "TCP_IDLE_TIMEOUT",
+                &var_or("TCP_IDLE_TIMEOUT", "10"),
+            )?),
+            dot_addresses: optional_addresses("DOT_LISTEN")?,
+            doq_addresses: optional_addresses("DOQ_LISTEN")?,
+            doh_addresses: optional_addresses("DOH_LISTEN")?,
+            doh_path: var_or("DOH_PATH", "/dns-query"),
+            doh_plaintext: parse_bool("DOH_PLAINTEXT", &var_or("DOH_PLAINTEXT", "false"))?,
+            tls: tls_settings()?,
+            unix_stream_path: env::var("UNIX_STREAM_PATH").ok().map(PathBuf::from),
+            unix_datagram_path: env::var("UNIX_DATAGRAM_PATH").ok().map(PathBuf::from),
+            unix_socket_mode: parse_mode("UNIX_SOCKET_MODE", &var_or("UNIX_SOCKET_MODE", "660"))?,
+            proxy_protocol_trusted: parse_networks(
+                "PROXY_PROTOCOL_TRUSTED",
+                &var_or("PROXY_PROTOCOL_TRUSTED", ""),
+            )?,
+            shutdown_grace_period: Duration::from_secs(parse_number(
+                "SHUTDOWN_GRACE_PERIOD",
+                &var_or("SHUTDOWN_GRACE_PERIOD", "15"),
+            )?),
+        };
+
+        // UPSTREAM_CA is trusted by the TLS upstreams without their own CA bundle
+        if let Ok(ca) = env::var("UPSTREAM_CA") {
+            let routed = config
+                .routes
+                .iter_mut()
+                .flat_map(|r| r.upstreams.iter_mut());
+            for upstream in config.upstreams.iter_mut().chain(routed) {
+                if upstream.scheme.uses_tls() && upstream.ca.is_none() {
+                    upstream.ca = Some(PathBuf::from(&ca));
+                }
+            }
+        }
+
+        let timeouts = config.upstream_timeouts;
+        if [timeouts.connect, timeouts.handshake, timeouts.query].contains(&Duration::ZERO) {
+            return Err(invalid(String::from(
+                "UPSTREAM_CONNECT_TIMEOUT, UPSTREAM_HANDSHAKE_TIMEOUT and UPSTREAM_QUERY_TIMEOUT must be at least 1",
+            )));
+        }
+        if config.health_check_interval.is_zero() {
+            return Err(invalid(String::from(
+                "HEALTH_CHECK_INTERVAL must be at least 1",
+            )));
+        }
+        // no permit could ever be acquired: the UDP servers would never answer
+        if config.udp_max_in_flight == 0 {
+            return Err(invalid(String::from(
+                "UDP_MAX_IN_FLIGHT must be at least 1",
+            )));
+        }
+        if config.udp_sockets == 0 {
+            return Err(invalid(String::from("UDP_SOCKETS must be at least 1")));
+        }
+        if !config.dot_addresses.is_empty() && config.tls.is_none() {
+            return Err(invalid(String::from(
+                "DOT_LISTEN needs TLS_CERTIFICATE and TLS_PRIVATE_KEY",
+            )));
+        }
+        if !config.doq_addresses.is_empty() && config.tls.is_none() {
+            return Err(invalid(String::from(
+                "DOQ_LISTEN needs TLS_CERTIFICATE and TLS_PRIVATE_KEY",
+            )));
+        }
+        if !config.doh_addresses.is_empty() && !config.doh_plaintext && config.tls.is_none() {
+            return Err(invalid(String::from(
+                "DOH_LISTEN needs TLS_CERTIFICATE and TLS_PRIVATE_KEY, or DOH_PLAINTEXT=true",
+            )));
+        }
+        Ok(config)
+    }
+}
+
+// UPSTREAMS, or the single upstream of DOT_SERVER_ADDRESS and DOT_SERVER_NAME
+fn upstreams() -> io::Result<Vec<UpstreamSettings>> {
+    match env::var("UPSTREAMS") {
+        Ok(list) => parse_upstreams("UPSTREAMS", &list),
+        Err(_) => Ok(vec![UpstreamSettings::new(
+            required("DOT_SERVER_ADDRESS")?,
+            required("DOT_SERVER_NAME")?,
+        )]),
+    }
+}
+
+// Comma separated list of upstream URLs, tried in order,
+// e.g. "tls://1.1.1.1?name=one.one.one.one,https://dns.google/dns-query,udp://10.0.0.2"
+fn parse_upstreams(name: &str, list: &str) -> io::Result<Vec<UpstreamSettings>> {
+    let upstreams = list
+        .split(',')
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+        .map(|entry| {
+            UpstreamSettings::from_str(entry)
+                .map_err(|e| invalid(format!("Invalid upstream in {}: {}: {}", name, entry, e)))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    if upstreams.is_empty() {
+        return Err(invalid(format!("No upstream found in {}", name)));
+    }
+    Ok(upstreams)
+}
+
+// Semicolon separated domains, or networks for their reverse zone, each with its
+// upstreams, e.g. "testinternal.alfa=udp://10.0.0.2;10.0.0.0/8=udp://10.0.0.2,tcp://10.0.0.3"
+fn parse_routes(list: &str) -> io::Result<Vec<Route>> {
+    let mut routes: Vec<Route> = vec![];
+    for entry in list.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+        let error = |e: &str| invalid(format!("Invalid route in ROUTES: {}: {}", entry, e));
+        let (domain, upstreams) = entry
+            .split_once('=')
+            .ok_or_else(|| error("expected domain=upstreams"))?;
+        let domain = match Network::from_str(domain.trim()) {
+            Ok(network) => network
+                .reverse_zone()
+                .ok_or_else(|| error("the prefix length must be a multiple of 8 (4 for IPv6)"))?,
+            Err(_) => domain.trim().to_ascii_lowercase(),
+        };
+        let domain = DomainName::parse_url(&domain);
+        if domain.labels.is_empty() {
+            return Err(error("empty domain"));
+        }
+        if routes.iter().any(|r| r.domain == domain) {
+            return Err(error("duplicate domain"));
+        }
+        let upstreams = parse_upstreams("ROUTES", upstreams)?;
+        routes.push(Route { domain, upstreams });
+    }
+    Ok(routes)
+}
+
+fn tls_settings() -> io::Result<Option<TlsSettings>> {
+    let certificate = match env::var("TLS_CERTIFICATE") {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => return Ok(None),
+    };
+
+    let reload_interval = Duration::from_secs(parse_number(
+        "TLS_RELOAD_INTERVAL",
+        &var_or("TLS_RELOAD_INTERVAL", "60"),
+    )?);
+    if reload_interval.is_zero() {
+        return Err(invalid(String::from(
+            "TLS_RELOAD_INTERVAL must be at least 1",
+        )));
+    }
+
+    Ok(Some(TlsSettings {
+        certificate,
+        private_key: PathBuf::from(required("TLS_PRIVATE_KEY")?),
+        client_ca: env::var("TLS_CLIENT_CA").ok().map(PathBuf::from),
+        reload_interval,
+    }))
+}
+
+fn var_or(name: &str, default: &str) -> String {
+    env::var(name).unwrap_or_else(|_| String::from(default))
+}
+
+// Listeners that are disabled unless their variable is set
+fn optional_addresses(name: &str) -> io::Result<Vec<SocketAddr>> {
+    match env::var(name) {
+        Ok(list) => parse_addresses(&list),
+        Err(_) => Ok(vec![]),
+    }
+}
+
+// An empty default disables the listeners
+fn addresses_or(name: &str, default: &str) -> io::Result<Vec<SocketAddr>> {
+    match env::var(name) {
+        Ok(list) => parse_addresses(&list),
+        Err(_) if default.is_empty() => Ok(vec![]),
+        Err(_) => parse_addresses(default),
+    }
+}
+
+fn required(name: &str) -> io::Result<String> {
+    env::var(name).map_err(|_| invalid(format!("Need to set {}", name)))
+}
+
+fn parse_number<T: FromStr>(name: &str, value: &str) -> io::Result<T> {
+    T::from_str(value.trim())
+        .map_err(|_| invalid(format!("Invalid number for {}: {}", name, value)))
+}
+
+// File permissions in octal, as used by chmod
+fn parse_mode(name: &str, value: &str) -> io::Result<u32> {
+    match u32::from_str_radix(value.trim(), 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(invalid(format!(
+            "Invalid file mode for {}: {}",
+            name, value
+        ))),
+    }
+}
+
+fn parse_bool(name: &str, value: &str) -> io::Result<bool> {
+    match value.trim().to_lowercase().as_str() {
+        "true" | "yes" | "1" => Ok(true),
+        "false" | "no" | "0" => Ok(false),
+        _ => Err(invalid(format!("Invalid boolean for {}: {}", name, value))),
+    }
+}
+
+// Comma separated list of addresses or CIDR ranges, e.g. "10.0.0.0/8,fd00::1"
+fn parse_networks(name: &str, list: &str) -> io::Result<Vec<Network>> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+        .map(|entry| {
+            Network::from_str(entry)
+                .map_err(|e| invalid(format!("Invalid network in {}: {}: {}", name, entry, e)))
+        })
+        .collect()
+}
+
+// Comma separated list of host:port entries, e.g. "0.0.0.0:53,[::]:53,localhost:5353"
+pub fn parse_addresses(list: &str) -> io::Result<Vec<SocketAddr>> {
+    let mut addresses = vec![];
+    for entry in list.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        match SocketAddr::from_str(entry) {
+            Ok(address) => addresses.push(address),
+            Err(_) => {
+                let resolved = entry
+                    .to_socket_addrs()
+                    .map_err(|e| invalid(format!("Invalid address {}: {}", entry, e)))?;
+                addresses.extend(resolved);
+            }
+        }
+    }
+
+    if addresses.is_empty() {
+        return Err(invalid(format!("No address found in \"{}\"", list)));
+    }
+    Ok(addresses)
+}
+
+pub fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{parse_addresses, parse_networks, parse_routes, parse_upstreams};
+    use std::net::SocketAddr;
+
+    #[test]
+    fn test_parse_addresses() {
+        let a = parse_addresses("0.0.0.0:53, [::]:53,127.0.0.1:5353,[::1]:853").unwrap();
+        let expected: Vec<SocketAddr> = vec![
+            "0.0.0.0:53".parse().unwrap(),
+            "[::]:53".parse().unwrap(),
+            "127.0.0.1:5353".parse().unwrap(),
+            "[::1]:853".parse().unwrap(),
+        ];
+        assert_eq!(a, expected);
+
+        let a = parse_addresses("localhost:53").unwrap();
+        assert!(a.iter().all(|x| x.ip().is_loopback() && x.port() == 53));
+
+        assert!(parse_addresses("").is_err());
+        assert!(parse_addresses("0.0.0.0").is_err());
+        assert!(parse_addresses("[::]:99999").is_err());
+    }
+
+    #[test]
+    fn test_parse_networks() {
+        let n = parse_networks("X", "10.0.0.0/8, 192.0.2.1,fd00::/8").unwrap();
+        assert_eq!(n.len(), 3);
+        assert!(n[1].contains("192.0.2.1".parse().unwrap()));
+
+        assert!(parse_networks("X", "").unwrap().is_empty());
+        assert!(parse_networks("X", "10.0.0.0/8,10.0.0").is_err());
+    }
+
+    #[test]
+    fn test_parse_upstreams() {
+        let u = parse_upstreams(
+            "UPSTREAMS",
+            "tls://1.1.1.1?name=one.one.one.one, tls://dns.google:853",
+        )
+        .unwrap();
+        assert_eq!(u.len(), 2);
+        assert_eq!(u[0].address, "1.1.1.1:853");
+        assert_eq!(u[1].name, "dns.google");
+
+        assert!(parse_upstreams("UPSTREAMS", " , ").is_err());
+        assert!(parse_upstreams("UPSTREAMS", "tls://dns.google,1.1.1.1:853").is_err());
+    }
+
+    #[test]
+    fn test_parse_routes() {
+        let routes = parse_routes(
+            "TestInternal.alfa=udp://127.0.0.1:5553,tcp://127.0.0.1:5553; \
+             192.168.0.0/16=udp://192.168.0.1;fd00::/8=udp://[fd00::1]",
+        )
+        .unwrap();
+        let domains: Vec<String> = routes.iter().map(|r| r.domain.to_string()).collect();
+        assert_eq!(
+            domains,
+            ["testinternal.alfa", "168.192.in-addr.arpa", "d.f.ip6.arpa"]
+        );
+        assert_eq!(routes[0].upstreams.len(), 2);
+        assert_eq!(routes[1].upstreams[0].address, "192.168.0.1:53");
+
+        assert!(parse_routes("").unwrap().is_empty());
+        assert!(parse_routes("testinternal.alfa").is_err());
+        assert!(parse_routes("testinternal.alfa=").is_err());
+        assert!(parse_routes(".=udp://10.0.0.2").is_err());
+        assert!(parse_routes("172.16.0.0/12=udp://10.0.0.2").is_err());
+        assert!(parse_routes("a.alfa=udp://10.0.0.2;A.alfa=udp://10.0.0.3").is_err());
+    }
+}
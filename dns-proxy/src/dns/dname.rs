@@ -1,10 +1,24 @@
-use crate::dns::MessageBytes;
-use bytes::{Buf, BufMut, Bytes, BytesMut};
-use std::str;
+use crate::dns::{get_u8, take, MessageBytes};
+use bytes::{BufMut, Bytes, BytesMut};
+use std::fmt;
+use std::io;
+
+// https://datatracker.ietf.org/doc/html/rfc1035#section-2.3.4
+const MAX_NAME_LENGTH: usize = 255;
+
+// https://datatracker.ietf.org/doc/html/rfc1035#section-4.1.4
+// Pointers followed for one name: plenty for real messages, and a pointer loop stops
+// there instead of going on forever
+const MAX_POINTERS: usize = 16;
 
 #[derive(Debug, PartialEq, Hash, Eq, Clone)]
 pub struct DomainName {
-    pub labels: Vec<String>,
+    // as received: labels may hold any byte, not only UTF-8
+    pub labels: Vec<Vec<u8>>,
+}
+
+fn invalid_name(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
 }
 
 impl DomainName {
@@ -14,51 +28,105 @@ impl DomainName {
     pub fn parse_url(url: &str) -> Self {
         let mut v = vec![];
         for label in url.split(".") {
-            if label.len() == 0 {
+            if label.is_empty() {
                 continue;
             }
-            v.push(String::from(label));
+            v.push(label.as_bytes().to_vec());
         }
 
         DomainName { labels: v }
     }
-    fn internal_parse(
-        mut labels: Vec<String>,
-        original: &mut Bytes,
-        buffer: &mut Bytes,
-    ) -> Vec<String> {
+    pub fn parse(mp: &mut MessageBytes) -> io::Result<Self> {
+        let mut labels = vec![];
+        let mut length = 1;
+        let mut pointers = 0;
+        // the rest of the name, once a pointer was followed
+        let mut pointed: Option<Bytes> = None;
         loop {
-            let len = buffer.get_u8() as usize;
-            if len == 0 {
-                break;
-            }
-
-            if len >> 6 & 0b11 == 3 {
-                let offset = (len & 0b111111) << 8 | buffer.get_u8() as usize;
-                let mut new_buffer = original.clone();
-                new_buffer.advance(offset);
-                labels = DomainName::internal_parse(labels, original, &mut new_buffer);
-                break;
-            } else {
-                let label_buff = buffer.copy_to_bytes(len);
-                let label = str::from_utf8(&label_buff[..]).expect("Invalid UTF-8");
-                labels.push(String::from(label));
+            let buffer = pointed.as_mut().unwrap_or(&mut mp.buffer);
+            let len = get_u8(buffer)? as usize;
+            match len >> 6 {
+                0 if len == 0 => break,
+                0 => {
+                    length += len + 1;
+                    if length > MAX_NAME_LENGTH {
+                        return Err(invalid_name("Name too long"));
+                    }
+                    labels.push(take(buffer, len)?.to_vec());
+                }
+                0b11 => {
+                    let offset = (len & 0b111111) << 8 | get_u8(buffer)? as usize;
+                    pointers += 1;
+                    if pointers > MAX_POINTERS || offset >= mp.original.len() {
+                        return Err(invalid_name("Invalid compression pointer"));
+                    }
+                    pointed = Some(mp.original.slice(offset..));
+                }
+                _ => return Err(invalid_name("Unsupported label type")),
             }
         }
-        labels
-    }
-    pub fn parse(mp: &mut MessageBytes) -> Self {
-        let labels = DomainName::internal_parse(vec![], &mut mp.original, &mut mp.buffer);
-        DomainName { labels }
+        Ok(DomainName { labels })
     }
 
     pub fn write(&self, mut msg: BytesMut) -> BytesMut {
         for label in self.labels.iter() {
             msg.put_u8(label.len() as u8);
-            msg.put_slice(label.as_bytes())
+            msg.put_slice(label)
         }
         msg.put_u8(0);
         msg
     }
 }
-// tests available at question.rs and record.rs
+
+// For logs and configuration: the labels joined by dots
+impl fmt::Display for DomainName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, label) in self.labels.iter().enumerate() {
+            if i > 0 {
+                f.write_str(".")?;
+            }
+            f.write_str(&String::from_utf8_lossy(label))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dns::dname::DomainName;
+    use crate::dns::MessageBytes;
+    use bytes::Bytes;
+
+    fn parse(b: &'static [u8]) -> std::io::Result<DomainName> {
+        DomainName::parse(&mut MessageBytes::from_bytes(Bytes::from(b)))
+    }
+
+    #[test]
+    fn test_invalid_names() {
+        // a pointer to itself, and two pointing to each other
+        assert!(parse(b"\xc0\x00").is_err());
+        assert!(parse(b"\xc0\x02\xc0\x00").is_err());
+        // past the end of the message
+        assert!(parse(b"\x04alf").is_err());
+        assert!(parse(b"\x04alfa").is_err());
+        assert!(parse(b"\xc0\x10").is_err());
+        assert!(parse(b"\xc0").is_err());
+        // too long, with 64 bytes labels (reserved label types)
+        assert!(parse(b"\x40").is_err());
+        let mut long = vec![];
+        for _ in 0..5 {
+            long.push(63u8);
+            long.extend_from_slice(&[b'a'; 63]);
+        }
+        long.push(0);
+        let long: &'static [u8] = Vec::leak(long);
+        assert!(parse(long).is_err());
+    }
+
+    #[test]
+    fn test_binary_labels() {
+        let name = parse(b"\x02\xff\x00\x03com\x00").unwrap();
+        assert_eq!(name.labels, vec![vec![0xff, 0x00], b"com".to_vec()]);
+        assert_eq!(name.to_string(), "\u{fffd}\u{0}.com");
+    }
+}
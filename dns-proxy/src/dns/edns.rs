@@ -0,0 +1,130 @@
+use crate::dns::message::Message;
+use crate::dns::QType;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+// https://datatracker.ietf.org/doc/html/rfc6891#section-6.1.2
+// The OPT pseudo record keeps its options in RDATA as {OPTION-CODE, OPTION-LENGTH, OPTION-DATA}
+
+// https://datatracker.ietf.org/doc/html/rfc7828
+pub const TCP_KEEPALIVE: u16 = 11;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Bytes,
+}
+
+pub fn parse_options(mut data: Bytes) -> Vec<EdnsOption> {
+    let mut options = vec![];
+    while data.remaining() >= 4 {
+        let code = data.get_u16();
+        let length = data.get_u16() as usize;
+        if data.remaining() < length {
+            break;
+        }
+        options.push(EdnsOption {
+            code,
+            data: data.copy_to_bytes(length),
+        });
+    }
+    options
+}
+
+pub fn write_options(options: &[EdnsOption]) -> Bytes {
+    let mut msg = BytesMut::new();
+    for option in options {
+        msg.put_u16(option.code);
+        msg.put_u16(option.data.len() as u16);
+        msg.put_slice(option.data.as_ref());
+    }
+    msg.freeze()
+}
+
+pub fn find_option(message: &Message, code: u16) -> Option<Bytes> {
+    let opt = message
+        .additional_records
+        .iter()
+        .find(|r| r.resource_type == QType::OPT)?;
+
+    parse_options(opt.resource_data.clone())
+        .into_iter()
+        .find(|o| o.code == code)
+        .map(|o| o.data)
+}
+
+// Replaces (or removes, when data is None) an option of the OPT record.
+// Messages without EDNS are left untouched.
+pub fn set_option(message: &mut Message, code: u16, data: Option<Bytes>) {
+    if let Some(opt) = message
+        .additional_records
+        .iter_mut()
+        .find(|r| r.resource_type == QType::OPT)
+    {
+        let mut options: Vec<EdnsOption> = parse_options(opt.resource_data.clone())
+            .into_iter()
+            .filter(|o| o.code != code)
+            .collect();
+        if let Some(data) = data {
+            options.push(EdnsOption { code, data });
+        }
+        opt.resource_data = write_options(&options);
+        opt.data_length = opt.resource_data.len() as u16;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dns::edns::{find_option, parse_options, set_option, write_options, EdnsOption};
+    use crate::dns::message::Message;
+    use crate::dns::MessageBytes;
+    use bytes::{Bytes, BytesMut};
+
+    #[test]
+    fn test_options() {
+        let b =
+            Bytes::from(&b"\x00\x0b\x00\x00\x00\x0a\x00\x08\x01\x02\x03\x04\x05\x06\x07\x08"[..]);
+        let options = parse_options(b.clone());
+        assert_eq!(
+            options,
+            vec![
+                EdnsOption {
+                    code: 11,
+                    data: Bytes::new()
+                },
+                EdnsOption {
+                    code: 10,
+                    data: Bytes::from(&b"\x01\x02\x03\x04\x05\x06\x07\x08"[..])
+                },
+            ]
+        );
+        assert_eq!(write_options(&options), b);
+
+        // truncated option is ignored
+        assert_eq!(
+            parse_options(Bytes::from(&b"\x00\x0a\x00\x08\x01"[..])),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_set_option() {
+        // query for example.com with an OPT record carrying an empty edns-tcp-keepalive
+        let b = Bytes::from(
+            &b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x01\x07example\x03com\x00\x00\x01\x00\x01\x00\x00\x29\x10\x00\x00\x00\x00\x00\x00\x04\x00\x0b\x00\x00"[..],
+        );
+        let mut m = Message::parse(&mut MessageBytes::from_bytes(b)).unwrap();
+        assert_eq!(find_option(&m, 11), Some(Bytes::new()));
+        assert_eq!(find_option(&m, 10), None);
+
+        set_option(&mut m, 11, Some(Bytes::from(&b"\x00\x64"[..])));
+        let w = m.write(BytesMut::new());
+        let m = Message::parse(&mut MessageBytes::from_bytes(w.freeze())).unwrap();
+        assert_eq!(find_option(&m, 11), Some(Bytes::from(&b"\x00\x64"[..])));
+        assert_eq!(m.additional_records[0].resource_class, 4096);
+
+        let mut m = m;
+        set_option(&mut m, 11, None);
+        assert_eq!(find_option(&m, 11), None);
+        assert_eq!(m.additional_records[0].resource_data, Bytes::new());
+    }
+}
@@ -1,21 +1,18 @@
-use crate::dns::MessageBytes;
-use bytes::{Buf, BufMut, BytesMut};
+use crate::dns::{get_u16, MessageBytes};
+use bytes::{BufMut, BytesMut};
+use std::io;
 
-#[derive(PartialEq, Debug, Copy, Clone)]
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
 pub enum ResponseCode {
-    NoError = 0,
-    FormatError = 1,
-    ServerFailure = 2,
-    NameError = 3,
-    NotImplemented = 4,
-    Refused = 5,
-    // 6 - 15 Reserved for future use
-}
-
-impl Default for ResponseCode {
-    fn default() -> Self {
-        ResponseCode::NotImplemented
-    }
+    NoError,
+    FormatError,
+    ServerFailure,
+    NameError,
+    #[default]
+    NotImplemented,
+    Refused,
+    // 6 - 15: YXDOMAIN, NOTAUTH... (RFC 2136) and unassigned ones, kept as received
+    Other(u16),
 }
 
 impl ResponseCode {
@@ -25,14 +22,27 @@ impl ResponseCode {
             1 => ResponseCode::FormatError,
             2 => ResponseCode::ServerFailure,
             3 => ResponseCode::NameError,
+            4 => ResponseCode::NotImplemented,
             5 => ResponseCode::Refused,
-            _ => ResponseCode::NotImplemented,
+            _ => ResponseCode::Other(var),
+        }
+    }
+
+    fn to_u16(self) -> u16 {
+        match self {
+            ResponseCode::NoError => 0,
+            ResponseCode::FormatError => 1,
+            ResponseCode::ServerFailure => 2,
+            ResponseCode::NameError => 3,
+            ResponseCode::NotImplemented => 4,
+            ResponseCode::Refused => 5,
+            ResponseCode::Other(var) => var,
         }
     }
 }
 
 // https://datatracker.ietf.org/doc/html/rfc1035#section-4.1.1
-#[derive(Default, Debug, PartialEq)]
+#[derive(Default, Debug, PartialEq, Clone)]
 pub struct Header {
     pub id: u16,                       // ID - 16 bits
     pub question_response: u8,         // QR - 1 bit (0 = question, 1 = answer)
@@ -41,7 +51,7 @@ pub struct Header {
     pub truncation: bool,              // TC - 1 bit
     pub recursion_desired: bool,       // RD - 1 bit
     pub recursion_available: bool,     // RA - 1 bit
-    pub z: u8,                         // Z - 3 bits as zero
+    pub z: u8,                         // Z - 3 bits: zero, AD and CD (RFC 4035)
     pub response_code: ResponseCode,   // RCODE - 4 bits
     pub question_count: u16,           // QDCOUNT - 16 bits
     pub answer_count: u16,             // ANCOUNT - 16 bits
@@ -50,12 +60,12 @@ pub struct Header {
 }
 
 impl Header {
-    pub fn parse(mp: &mut MessageBytes) -> Self {
+    pub fn parse(mp: &mut MessageBytes) -> io::Result<Self> {
         let buffer = &mut mp.buffer;
-        let id = buffer.get_u16();
-        let flags = buffer.get_u16();
+        let id = get_u16(buffer)?;
+        let flags = get_u16(buffer)?;
         // println!("{:016b}", flags);
-        Header {
+        Ok(Header {
             id,
             question_response: (flags >> 15 & 0b1) as u8,
             opcode: (flags >> 11 & 0b1111) as u8,
@@ -63,24 +73,25 @@ impl Header {
             truncation: (flags >> 9 & 0b1) == 1,
             recursion_desired: (flags >> 8 & 0b1) == 1,
             recursion_available: (flags >> 7 & 0b1) == 1,
-            z: (flags >> 4 & 0b111) as u8, // not used
+            z: (flags >> 4 & 0b111) as u8,
             response_code: ResponseCode::from_u16(flags & 0b1111),
-            question_count: buffer.get_u16(),
-            answer_count: buffer.get_u16(),
-            nameserver_count: buffer.get_u16(),
-            additional_records_count: buffer.get_u16(),
-        }
+            question_count: get_u16(buffer)?,
+            answer_count: get_u16(buffer)?,
+            nameserver_count: get_u16(buffer)?,
+            additional_records_count: get_u16(buffer)?,
+        })
     }
 
     pub fn write(&self, mut msg: BytesMut) -> BytesMut {
-        let mut flags = 0 as u16;
+        let mut flags = 0u16;
         flags |= (self.question_response as u16) << 15;
         flags |= (self.opcode as u16) << 11;
         flags |= (self.authoritative_answer as u16) << 10;
         flags |= if self.truncation { 1 } else { 0 } << 9;
         flags |= if self.recursion_desired { 1 } else { 0 } << 8;
         flags |= if self.recursion_available { 1 } else { 0 } << 7;
-        flags |= (self.response_code as u16) & 0b1111;
+        flags |= (self.z as u16 & 0b111) << 4;
+        flags |= self.response_code.to_u16() & 0b1111;
 
         msg.put_u16(self.id);
         msg.put_u16(flags);
@@ -103,40 +114,49 @@ mod tests {
     fn test_header_read() {
         let b = Bytes::from(&b"09\tE\x00\x00\x00\x00\x00\x00\x00\x00"[..]);
         let mut a = MessageBytes::from_bytes(b);
-        let hs = Header::parse(&mut a);
+        let hs = Header::parse(&mut a).unwrap();
         assert_eq!(hs.id, 12345);
         assert_eq!(hs.question_response, 0);
         assert_eq!(hs.opcode, 1);
         assert_eq!(hs.authoritative_answer, 0);
-        assert_eq!(hs.truncation, false);
-        assert_eq!(hs.recursion_desired, true);
+        assert!(!hs.truncation);
+        assert!(hs.recursion_desired);
         assert_eq!(hs.response_code, ResponseCode::Refused);
 
         let b = Bytes::from(&b"\xd41\xfd\x00\x00\x00\x00\x00\x00\x00\x00\x00"[..]);
         let mut a = MessageBytes::from_bytes(b);
-        let hs = Header::parse(&mut a);
+        let hs = Header::parse(&mut a).unwrap();
         assert_eq!(hs.id, 54321);
         assert_eq!(hs.question_response, 1);
         assert_eq!(hs.opcode, 15);
         assert_eq!(hs.authoritative_answer, 1);
-        assert_eq!(hs.truncation, false);
-        assert_eq!(hs.recursion_desired, true);
+        assert!(!hs.truncation);
+        assert!(hs.recursion_desired);
         assert_eq!(hs.response_code, ResponseCode::NoError);
 
         let b = Bytes::from(&b"\x00\x00\x02\x03\x00\x03\x00\x17\x00\x05\x00\x07"[..]);
         let mut a = MessageBytes::from_bytes(b);
-        let hs = Header::parse(&mut a);
+        let hs = Header::parse(&mut a).unwrap();
         assert_eq!(hs.id, 0);
         assert_eq!(hs.question_response, 0);
         assert_eq!(hs.opcode, 0);
         assert_eq!(hs.authoritative_answer, 0);
-        assert_eq!(hs.truncation, true);
-        assert_eq!(hs.recursion_desired, false);
+        assert!(hs.truncation);
+        assert!(!hs.recursion_desired);
         assert_eq!(hs.response_code, ResponseCode::NameError);
         assert_eq!(hs.question_count, 3);
         assert_eq!(hs.answer_count, 23);
         assert_eq!(hs.nameserver_count, 5);
         assert_eq!(hs.additional_records_count, 7);
+
+        // NOTAUTH (RFC 2136) is kept as received
+        let b = Bytes::from(&b"\x00\x00\x80\x09\x00\x00\x00\x00\x00\x00\x00\x00"[..]);
+        let hs = Header::parse(&mut MessageBytes::from_bytes(b.clone())).unwrap();
+        assert_eq!(hs.response_code, ResponseCode::Other(9));
+        assert_eq!(hs.write(BytesMut::new()), b);
+
+        let b = Bytes::from(&b"\x00\x00\x02\x03\x00\x03\x00\x17\x00\x05\x00"[..]);
+        assert!(Header::parse(&mut MessageBytes::from_bytes(b)).is_err());
     }
 
     #[test]
@@ -161,7 +181,7 @@ mod tests {
         let w = a.write(BytesMut::new());
 
         let mut mp = MessageBytes::from_bytes(w.freeze());
-        assert_eq!(Header::parse(&mut mp), a);
+        assert_eq!(Header::parse(&mut mp).unwrap(), a);
 
         let a = Header {
             id: 432,
@@ -171,7 +191,8 @@ mod tests {
             truncation: false,
             recursion_desired: true,
             recursion_available: false,
-            z: 0,
+            // AD
+            z: 0b010,
             response_code: ResponseCode::ServerFailure,
             question_count: 1,
             answer_count: 3,
@@ -182,6 +203,6 @@ mod tests {
         let w = a.write(BytesMut::new());
 
         let mut mp = MessageBytes::from_bytes(w.freeze());
-        assert_eq!(Header::parse(&mut mp), a);
+        assert_eq!(Header::parse(&mut mp).unwrap(), a);
     }
 }
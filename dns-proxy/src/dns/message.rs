@@ -3,9 +3,10 @@ use crate::dns::question::Question;
 use crate::dns::record::ResourceRecord;
 use crate::dns::MessageBytes;
 use bytes::BytesMut;
+use std::io;
 
 // https://datatracker.ietf.org/doc/html/rfc1035#section-4.1
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Message {
     pub header: Header,
     pub question: Vec<Question>,
@@ -15,40 +16,38 @@ pub struct Message {
 }
 
 impl Message {
-    pub fn parse(mp: &mut MessageBytes) -> Self {
-        let header = Header::parse(mp);
+    // The counts come from the message itself: the sections grow as records are parsed,
+    // and a count larger than the message ends in an error
+    pub fn parse(mp: &mut MessageBytes) -> io::Result<Self> {
+        let header = Header::parse(mp)?;
 
-        let question_count = header.question_count as usize;
-        let mut question = Vec::with_capacity(question_count);
-        for _ in 0..question_count {
-            question.push(Question::parse(mp));
+        let mut question = Vec::new();
+        for _ in 0..header.question_count {
+            question.push(Question::parse(mp)?);
         }
 
-        let answer_count = header.answer_count as usize;
-        let mut answer = Vec::with_capacity(answer_count);
-        for _ in 0..answer_count {
-            answer.push(ResourceRecord::parse(mp));
+        let mut answer = Vec::new();
+        for _ in 0..header.answer_count {
+            answer.push(ResourceRecord::parse(mp)?);
         }
 
-        let authority_count = header.nameserver_count as usize;
-        let mut authority = Vec::with_capacity(authority_count);
-        for _ in 0..authority_count {
-            authority.push(ResourceRecord::parse(mp));
+        let mut authority = Vec::new();
+        for _ in 0..header.nameserver_count {
+            authority.push(ResourceRecord::parse(mp)?);
         }
 
-        let additional_records_count = header.additional_records_count as usize;
-        let mut additional_records = Vec::with_capacity(additional_records_count);
-        for _ in 0..additional_records_count {
-            additional_records.push(ResourceRecord::parse(mp));
+        let mut additional_records = Vec::new();
+        for _ in 0..header.additional_records_count {
+            additional_records.push(ResourceRecord::parse(mp)?);
         }
 
-        Message {
+        Ok(Message {
             header,
             question,
             answer,
             authority,
             additional_records,
-        }
+        })
     }
 
     pub fn write(&self, msg: BytesMut) -> BytesMut {
@@ -68,7 +67,6 @@ impl Message {
 #[cfg(test)]
 mod tests {
     use crate::dns::dname::DomainName;
-    use crate::dns::header::ResponseCode::NoError;
     use crate::dns::header::{Header, ResponseCode};
     use crate::dns::message::Message;
     use crate::dns::question::Question;
@@ -106,11 +104,17 @@ mod tests {
             additional_records: vec![],
         };
         let mut a = MessageBytes::from_bytes(b);
-        assert_eq!(Message::parse(&mut a), k);
+        assert_eq!(Message::parse(&mut a).unwrap(), k);
 
         let w = k.write(BytesMut::new());
 
         let mut mp = MessageBytes::from_bytes(w.freeze());
-        assert_eq!(Message::parse(&mut mp), k);
+        assert_eq!(Message::parse(&mut mp).unwrap(), k);
+
+        // one more question than in the message
+        let b = Bytes::from(
+            &b"[\xa3\x01\0\0\x02\0\0\0\0\0\0\x04mail\x06google\x03com\0\0\x1c\0\x01"[..],
+        );
+        assert!(Message::parse(&mut MessageBytes::from_bytes(b)).is_err());
     }
 }
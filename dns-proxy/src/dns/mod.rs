@@ -1,6 +1,8 @@
-use bytes::Bytes;
+use bytes::{Buf, Bytes};
+use std::io;
 
 pub mod dname;
+pub mod edns;
 pub mod header;
 pub mod message;
 pub mod question;
@@ -20,8 +22,71 @@ impl MessageBytes {
         }
     }
 }
-#[derive(PartialEq, Debug, Copy, Clone, Hash, Eq)]
-pub enum QType {
+
+// Messages come from anyone (clients and upstreams): reading past their end is an error,
+// answered with FORMERR or SERVFAIL, instead of a panic
+fn too_short() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Message too short")
+}
+
+fn get_u8(buffer: &mut Bytes) -> io::Result<u8> {
+    match buffer.remaining() {
+        0 => Err(too_short()),
+        _ => Ok(buffer.get_u8()),
+    }
+}
+
+fn get_u16(buffer: &mut Bytes) -> io::Result<u16> {
+    match buffer.remaining() {
+        0..=1 => Err(too_short()),
+        _ => Ok(buffer.get_u16()),
+    }
+}
+
+fn get_u32(buffer: &mut Bytes) -> io::Result<u32> {
+    match buffer.remaining() {
+        0..=3 => Err(too_short()),
+        _ => Ok(buffer.get_u32()),
+    }
+}
+
+fn take(buffer: &mut Bytes, length: usize) -> io::Result<Bytes> {
+    if buffer.remaining() < length {
+        return Err(too_short());
+    }
+    Ok(buffer.split_to(length))
+}
+
+// The types every record and question keeps as received: the ones not listed here are
+// Unknown, with their value, so they are written back (and cached) unchanged
+macro_rules! qtypes {
+    ($($name:ident = $value:literal,)*) => {
+        #[allow(clippy::upper_case_acronyms)]
+        #[derive(PartialEq, Debug, Copy, Clone, Hash, Eq)]
+        pub enum QType {
+            $($name,)*
+            Unknown(u16),
+        }
+
+        impl QType {
+            fn from_u16(var: u16) -> Self {
+                match var {
+                    $($value => QType::$name,)*
+                    _ => QType::Unknown(var),
+                }
+            }
+
+            pub fn to_u16(self) -> u16 {
+                match self {
+                    $(QType::$name => $value,)*
+                    QType::Unknown(var) => var,
+                }
+            }
+        }
+    };
+}
+
+qtypes! {
     A = 1,
     NS = 2,
     CNAME = 5,
@@ -77,65 +142,3 @@ pub enum QType {
     TA = 32768,
     DLV = 32769,
 }
-
-impl QType {
-    fn from_u16(var: u16) -> Self {
-        match var {
-            1 => QType::A,
-            2 => QType::NS,
-            5 => QType::CNAME,
-            6 => QType::SOA,
-            10 => QType::NULL,
-            12 => QType::PTR,
-            13 => QType::HINFO,
-            15 => QType::MX,
-            16 => QType::TXT,
-            17 => QType::RP,
-            18 => QType::AFSDB,
-            24 => QType::SIG,
-            25 => QType::KEY,
-            28 => QType::AAAA,
-            29 => QType::LOC,
-            33 => QType::SRV,
-            35 => QType::NAPTR,
-            36 => QType::KX,
-            37 => QType::CERT,
-            38 => QType::A6,
-            39 => QType::DNAME,
-            41 => QType::OPT,
-            42 => QType::APL,
-            43 => QType::DS,
-            44 => QType::SSHFP,
-            45 => QType::IPSECKEY,
-            46 => QType::RRSIG,
-            47 => QType::NSEC,
-            48 => QType::DNSKEY,
-            49 => QType::DHCID,
-            50 => QType::NSEC3,
-            51 => QType::NSEC3PARAM,
-            52 => QType::TLSA,
-            53 => QType::HIP53,
-            55 => QType::HIP55,
-            59 => QType::CDS,
-            60 => QType::CDNSKEY,
-            61 => QType::OPENPGPKEY,
-            62 => QType::CSYNC,
-            63 => QType::ZONEMD,
-            64 => QType::SVCB,
-            65 => QType::HTTPS,
-            99 => QType::SPF,
-            108 => QType::EUI48,
-            109 => QType::EUI64,
-            249 => QType::TKEY,
-            250 => QType::TSIG,
-            251 => QType::IXFR,
-            252 => QType::AXFR,
-            255 => QType::ANY,
-            256 => QType::URI,
-            257 => QType::CAA,
-            32768 => QType::TA,
-            32769 => QType::DLV,
-            _ => QType::NULL,
-        }
-    }
-}
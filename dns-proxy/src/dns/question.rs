@@ -1,6 +1,7 @@
 use crate::dns::dname::DomainName;
-use crate::dns::{MessageBytes, QType};
-use bytes::{Buf, BufMut, BytesMut};
+use crate::dns::{get_u16, MessageBytes, QType};
+use bytes::{BufMut, BytesMut};
+use std::io;
 
 #[derive(Debug, PartialEq, Hash, Eq, Clone)]
 pub struct Question {
@@ -10,18 +11,18 @@ pub struct Question {
 }
 
 impl Question {
-    pub fn parse(mp: &mut MessageBytes) -> Self {
-        Question {
-            domain_name: DomainName::parse(mp),
-            query_type: QType::from_u16(mp.buffer.get_u16()),
-            query_class: mp.buffer.get_u16(),
-        }
+    pub fn parse(mp: &mut MessageBytes) -> io::Result<Self> {
+        Ok(Question {
+            domain_name: DomainName::parse(mp)?,
+            query_type: QType::from_u16(get_u16(&mut mp.buffer)?),
+            query_class: get_u16(&mut mp.buffer)?,
+        })
     }
 
-    pub fn write(&self, mut msg: BytesMut) -> BytesMut {
+    pub fn write(&self, msg: BytesMut) -> BytesMut {
         let mut msg = self.domain_name.write(msg);
 
-        msg.put_u16(self.query_type as u16);
+        msg.put_u16(self.query_type.to_u16());
         msg.put_u16(self.query_class);
 
         msg
@@ -39,22 +40,22 @@ mod tests {
     fn test_question_section_1domain_without_compression() {
         let b = Bytes::from(&b"\x03abc\x03com\x00\x00\x01\x00\x01"[..]);
         let mut a = MessageBytes::from_bytes(b);
-        let hs = Question::parse(&mut a);
-        assert_eq!(hs.domain_name.labels, vec!["abc", "com"]);
+        let hs = Question::parse(&mut a).unwrap();
+        assert_eq!(hs.domain_name.to_string(), "abc.com");
         assert_eq!(hs.query_type, QType::A);
         assert_eq!(hs.query_class, 1);
 
         let b = Bytes::from(&b"\x03abc\x03com\x00\x00\x0f\x00\x01"[..]);
         let mut a = MessageBytes::from_bytes(b);
-        let hs = Question::parse(&mut a);
-        assert_eq!(hs.domain_name.labels, vec!["abc", "com"]);
+        let hs = Question::parse(&mut a).unwrap();
+        assert_eq!(hs.domain_name.to_string(), "abc.com");
         assert_eq!(hs.query_type, QType::MX);
         assert_eq!(hs.query_class, 1);
 
         let b = Bytes::from(&b"\x04alfa\x04beta\x03abc\x03com\x00\x00\x1c\x00\x01"[..]);
         let mut a = MessageBytes::from_bytes(b);
-        let hs = Question::parse(&mut a);
-        assert_eq!(hs.domain_name.labels, vec!["alfa", "beta", "abc", "com"]);
+        let hs = Question::parse(&mut a).unwrap();
+        assert_eq!(hs.domain_name.to_string(), "alfa.beta.abc.com");
         assert_eq!(hs.query_type, QType::AAAA);
         assert_eq!(hs.query_class, 1);
     }
@@ -63,46 +64,46 @@ mod tests {
     fn test_question_section_domains_with_compression() {
         let b = Bytes::from(&b"\x04alfa\x04beta\x03abc\x03com\x00\x00\x1c\x00\x01\x04mail\xc0\n\x00\x0f\x00\x01\xc0\n\x00\x02\x00\x01"[..]);
         let mut a = MessageBytes::from_bytes(b);
-        let hs = Question::parse(&mut a);
-        assert_eq!(hs.domain_name.labels, vec!["alfa", "beta", "abc", "com"]);
+        let hs = Question::parse(&mut a).unwrap();
+        assert_eq!(hs.domain_name.to_string(), "alfa.beta.abc.com");
         assert_eq!(hs.query_type, QType::AAAA);
         assert_eq!(hs.query_class, 1);
 
-        let hs = Question::parse(&mut a);
-        assert_eq!(hs.domain_name.labels, vec!["mail", "abc", "com"]);
+        let hs = Question::parse(&mut a).unwrap();
+        assert_eq!(hs.domain_name.to_string(), "mail.abc.com");
         assert_eq!(hs.query_type, QType::MX);
         assert_eq!(hs.query_class, 1);
 
-        let hs = Question::parse(&mut a);
-        assert_eq!(hs.domain_name.labels, vec!["abc", "com"]);
+        let hs = Question::parse(&mut a).unwrap();
+        assert_eq!(hs.domain_name.to_string(), "abc.com");
         assert_eq!(hs.query_type, QType::NS);
         assert_eq!(hs.query_class, 1);
 
         let b = Bytes::from(&b"\x04alfa\x03net\x00\x00\x1c\x00\x01\x01x\xc0\x00\x00\x0f\x00\x01\xc0\x00\x00\x02\x00\x01\x04mail\xc0\x0e\x00\x0f\x00\x01\x04mail\xc0\x00\x00\x0f\x00\x01"[..]);
         let mut a = MessageBytes::from_bytes(b);
 
-        let hs = Question::parse(&mut a);
-        assert_eq!(hs.domain_name.labels, vec!["alfa", "net"]);
+        let hs = Question::parse(&mut a).unwrap();
+        assert_eq!(hs.domain_name.to_string(), "alfa.net");
         assert_eq!(hs.query_type, QType::AAAA);
         assert_eq!(hs.query_class, 1);
 
-        let hs = Question::parse(&mut a);
-        assert_eq!(hs.domain_name.labels, vec!["x", "alfa", "net"]);
+        let hs = Question::parse(&mut a).unwrap();
+        assert_eq!(hs.domain_name.to_string(), "x.alfa.net");
         assert_eq!(hs.query_type, QType::MX);
         assert_eq!(hs.query_class, 1);
 
-        let hs = Question::parse(&mut a);
-        assert_eq!(hs.domain_name.labels, vec!["alfa", "net"]);
+        let hs = Question::parse(&mut a).unwrap();
+        assert_eq!(hs.domain_name.to_string(), "alfa.net");
         assert_eq!(hs.query_type, QType::NS);
         assert_eq!(hs.query_class, 1);
 
-        let hs = Question::parse(&mut a);
-        assert_eq!(hs.domain_name.labels, vec!["mail", "x", "alfa", "net"]);
+        let hs = Question::parse(&mut a).unwrap();
+        assert_eq!(hs.domain_name.to_string(), "mail.x.alfa.net");
         assert_eq!(hs.query_type, QType::MX);
         assert_eq!(hs.query_class, 1);
 
-        let hs = Question::parse(&mut a);
-        assert_eq!(hs.domain_name.labels, vec!["mail", "alfa", "net"]);
+        let hs = Question::parse(&mut a).unwrap();
+        assert_eq!(hs.domain_name.to_string(), "mail.alfa.net");
         assert_eq!(hs.query_type, QType::MX);
         assert_eq!(hs.query_class, 1);
     }
@@ -119,7 +120,7 @@ mod tests {
         let w = a.write(BytesMut::new());
 
         let mut mp = MessageBytes::from_bytes(w.freeze());
-        assert_eq!(Question::parse(&mut mp), a);
+        assert_eq!(Question::parse(&mut mp).unwrap(), a);
 
         let a = Question {
             domain_name: DomainName::parse_url("mail.x.example.com"),
@@ -130,6 +131,15 @@ mod tests {
         let w = a.write(BytesMut::new());
 
         let mut mp = MessageBytes::from_bytes(w.freeze());
-        assert_eq!(Question::parse(&mut mp), a);
+        assert_eq!(Question::parse(&mut mp).unwrap(), a);
+    }
+
+    #[test]
+    fn test_unknown_type() {
+        // a private use type (RFC 6895) is written back unchanged
+        let b = Bytes::from(&b"\x03abc\x03com\x00\xff\x00\x00\x01"[..]);
+        let hs = Question::parse(&mut MessageBytes::from_bytes(b.clone())).unwrap();
+        assert_eq!(hs.query_type, QType::Unknown(65280));
+        assert_eq!(hs.write(BytesMut::new()), b);
     }
 }
@@ -1,6 +1,7 @@
 use crate::dns::dname::DomainName;
-use crate::dns::{MessageBytes, QType};
-use bytes::{Buf, BufMut, Bytes, BytesMut};
+use crate::dns::{get_u16, get_u32, take, MessageBytes, QType};
+use bytes::{BufMut, Bytes, BytesMut};
+use std::io;
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct ResourceRecord {
@@ -13,30 +14,32 @@ pub struct ResourceRecord {
 }
 
 impl ResourceRecord {
-    pub fn parse(mp: &mut MessageBytes) -> Self {
-        let domain_name = DomainName::parse(mp);
-        let resource_type = QType::from_u16(mp.buffer.get_u16());
-        let resource_class = mp.buffer.get_u16();
-        let ttl = mp.buffer.get_u32();
-        let data_length = mp.buffer.get_u16();
+    pub fn parse(mp: &mut MessageBytes) -> io::Result<Self> {
+        let domain_name = DomainName::parse(mp)?;
+        let resource_type = QType::from_u16(get_u16(&mut mp.buffer)?);
+        let resource_class = get_u16(&mut mp.buffer)?;
+        let ttl = get_u32(&mut mp.buffer)?;
+        let data_length = get_u16(&mut mp.buffer)?;
 
         // println!("{:?}", DomainName::parse(&mut mp.clone()));
-        let resource_data = mp.buffer.copy_to_bytes(data_length as usize);
+        let resource_data = take(&mut mp.buffer, data_length as usize)?;
+        let resource_data = expand_names(resource_type, resource_data, &mp.original)?;
+        let data_length = resource_data.len() as u16;
 
-        ResourceRecord {
+        Ok(ResourceRecord {
             domain_name,
             resource_type,
             resource_class,
             ttl,
             data_length,
             resource_data,
-        }
+        })
     }
 
-    pub fn write(&self, mut msg: BytesMut) -> BytesMut {
+    pub fn write(&self, msg: BytesMut) -> BytesMut {
         let mut msg = self.domain_name.write(msg);
 
-        msg.put_u16(self.resource_type as u16);
+        msg.put_u16(self.resource_type.to_u16());
         msg.put_u16(self.resource_class);
         msg.put_u32(self.ttl);
         msg.put_u16(self.resource_data.len() as u16);
@@ -46,33 +49,66 @@ impl ResourceRecord {
     }
 }
 
+// https://datatracker.ietf.org/doc/html/rfc3597#section-4
+// The names in the RDATA of these types may be compressed. They are expanded, since
+// the offsets they point to change when the record is written in another message.
+fn expand_names(resource_type: QType, data: Bytes, original: &Bytes) -> io::Result<Bytes> {
+    let names = match resource_type {
+        QType::NS | QType::CNAME | QType::PTR | QType::MX => 1,
+        // MNAME and RNAME, then the serial and times
+        QType::SOA => 2,
+        _ => return Ok(data),
+    };
+    let mut mp = MessageBytes {
+        original: original.clone(),
+        buffer: data,
+    };
+    let mut msg = BytesMut::new();
+    // the preference comes before the exchange
+    if resource_type == QType::MX {
+        msg.put_u16(get_u16(&mut mp.buffer)?);
+    }
+    for _ in 0..names {
+        msg = DomainName::parse(&mut mp)?.write(msg);
+    }
+    msg.put_slice(&mp.buffer);
+    Ok(msg.freeze())
+}
+
 #[cfg(test)]
 mod tests {
     use crate::dns::dname::DomainName;
     use crate::dns::record::ResourceRecord;
     use crate::dns::{MessageBytes, QType};
-    use bytes::{Bytes, BytesMut};
+    use bytes::{Buf, Bytes, BytesMut};
 
     #[test]
     fn test_record() {
         let b = Bytes::from(&b"\x02ns\x04alfa\x03net\x00\x00\x02\x00\x01\x00\x00\x0e\x10\x00\x02\xc0\x00\xc0\x03\x00\x05\x00\x01\x00\x00\x04\xb0\x00\x07\x04ip00\xc0\x03\xc0%\x00\x1c\x00\x01\x00\x00\x0e\x10\x00\x10\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x02mx\xc0\x03\x00\x0f\x00\x01\x00\x00\x0e\x10\x00\x07\x00\n\x03::1\x00\x03txt\xc0\x03\x00\x10\x00\x01\x00\x00\x0e\x10\x00\t\x08Test 001"[..]);
         let mut a = MessageBytes::from_bytes(b);
-        let hs = ResourceRecord::parse(&mut a);
-        assert_eq!(hs.domain_name.labels, vec!["ns", "alfa", "net"]);
+        let hs = ResourceRecord::parse(&mut a).unwrap();
+        assert_eq!(hs.domain_name.to_string(), "ns.alfa.net");
         assert_eq!(hs.resource_type, QType::NS);
         assert_eq!(hs.resource_class, 1);
         assert_eq!(hs.ttl, 3600);
-        assert_eq!(hs.resource_data, Bytes::from(&b"\xc0\0"[..]));
+        // expanded from a pointer to the start of the message
+        assert_eq!(
+            hs.resource_data,
+            Bytes::from(&b"\x02ns\x04alfa\x03net\0"[..])
+        );
 
-        let hs = ResourceRecord::parse(&mut a);
-        assert_eq!(hs.domain_name.labels, vec!["alfa", "net"]);
+        let hs = ResourceRecord::parse(&mut a).unwrap();
+        assert_eq!(hs.domain_name.to_string(), "alfa.net");
         assert_eq!(hs.resource_type, QType::CNAME);
         assert_eq!(hs.resource_class, 1);
         assert_eq!(hs.ttl, 1200);
-        assert_eq!(hs.resource_data, Bytes::from(&b"\x04ip00\xc0\x03"[..]));
+        assert_eq!(
+            hs.resource_data,
+            Bytes::from(&b"\x04ip00\x04alfa\x03net\0"[..])
+        );
 
-        let hs = ResourceRecord::parse(&mut a);
-        assert_eq!(hs.domain_name.labels, vec!["ip00", "alfa", "net"]);
+        let hs = ResourceRecord::parse(&mut a).unwrap();
+        assert_eq!(hs.domain_name.to_string(), "ip00.alfa.net");
         assert_eq!(hs.resource_type, QType::AAAA);
         assert_eq!(hs.resource_class, 1);
         assert_eq!(hs.ttl, 3600);
@@ -81,21 +117,55 @@ mod tests {
             Bytes::from(&b"\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\x01"[..])
         );
 
-        let hs = ResourceRecord::parse(&mut a);
-        assert_eq!(hs.domain_name.labels, vec!["mx", "alfa", "net"]);
+        let hs = ResourceRecord::parse(&mut a).unwrap();
+        assert_eq!(hs.domain_name.to_string(), "mx.alfa.net");
         assert_eq!(hs.resource_type, QType::MX);
         assert_eq!(hs.resource_class, 1);
         assert_eq!(hs.ttl, 3600);
         assert_eq!(hs.resource_data, Bytes::from(&b"\0\n\x03::1\0"[..]));
 
-        let hs = ResourceRecord::parse(&mut a);
-        assert_eq!(hs.domain_name.labels, vec!["txt", "alfa", "net"]);
+        let hs = ResourceRecord::parse(&mut a).unwrap();
+        assert_eq!(hs.domain_name.to_string(), "txt.alfa.net");
         assert_eq!(hs.resource_type, QType::TXT);
         assert_eq!(hs.resource_class, 1);
         assert_eq!(hs.ttl, 3600);
         assert_eq!(hs.resource_data, Bytes::from(&b"\x08Test 001"[..]));
     }
 
+    #[test]
+    fn test_expand_names() {
+        // alfa.net, then a SOA record with all its names compressed
+        let mut b = BytesMut::from(
+            &b"\x04alfa\x03net\x00\xc0\x00\x00\x06\x00\x01\x00\x00\x0e\x10\x00\x21"[..],
+        );
+        b.extend_from_slice(b"\x02ns\xc0\x00\x05admin\xc0\x00");
+        b.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0, 5]);
+        let mut mp = MessageBytes::from_bytes(b.freeze());
+        mp.buffer.advance(10);
+
+        let soa = ResourceRecord::parse(&mut mp).unwrap();
+        assert_eq!(soa.domain_name.to_string(), "alfa.net");
+        let mut expected =
+            BytesMut::from(&b"\x02ns\x04alfa\x03net\x00\x05admin\x04alfa\x03net\x00"[..]);
+        expected.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0, 5]);
+        assert_eq!(soa.resource_data, expected);
+        assert_eq!(soa.data_length as usize, expected.len());
+        assert!(mp.buffer.is_empty());
+    }
+
+    #[test]
+    fn test_invalid_rdata() {
+        // MX with only one byte of preference
+        let b = Bytes::from(&b"\x02mx\x00\x00\x0f\x00\x01\x00\x00\x0e\x10\x00\x01\x00"[..]);
+        assert!(ResourceRecord::parse(&mut MessageBytes::from_bytes(b)).is_err());
+        // NS pointing to itself
+        let b = Bytes::from(&b"\x02ns\x00\x00\x02\x00\x01\x00\x00\x0e\x10\x00\x02\xc0\x0e"[..]);
+        assert!(ResourceRecord::parse(&mut MessageBytes::from_bytes(b)).is_err());
+        // RDLENGTH past the end
+        let b = Bytes::from(&b"\x02ns\x00\x00\x10\x00\x01\x00\x00\x0e\x10\x00\x09\x08Test"[..]);
+        assert!(ResourceRecord::parse(&mut MessageBytes::from_bytes(b)).is_err());
+    }
+
     #[test]
     fn test_write() {
         // check only if the bits are right
@@ -112,7 +182,7 @@ mod tests {
         let w = a.write(BytesMut::new());
 
         let mut mp = MessageBytes::from_bytes(w.freeze());
-        assert_eq!(ResourceRecord::parse(&mut mp), a);
+        assert_eq!(ResourceRecord::parse(&mut mp).unwrap(), a);
 
         let b = Bytes::from(&b"xxxxxxxxxx"[..]);
         let a = ResourceRecord {
@@ -127,6 +197,6 @@ mod tests {
         let w = a.write(BytesMut::new());
 
         let mut mp = MessageBytes::from_bytes(w.freeze());
-        assert_eq!(ResourceRecord::parse(&mut mp), a);
+        assert_eq!(ResourceRecord::parse(&mut mp).unwrap(), a);
     }
 }
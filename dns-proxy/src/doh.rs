@@ -0,0 +1,346 @@
+use crate::framing::{MAX_QUERY_SIZE, MIN_MESSAGE_SIZE};
+use crate::server::{self, Cache};
+use crate::shutdown::Shutdown;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use bytes::{Bytes, BytesMut};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::Incoming;
+use hyper::header::{HeaderValue, CACHE_CONTROL, CONTENT_TYPE};
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use log::{debug, info};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+// https://datatracker.ietf.org/doc/html/rfc8484
+
+pub const DNS_MESSAGE: &str = "application/dns-message";
+
+pub const ALPN_H2: &[u8] = b"h2";
+pub const ALPN_HTTP1: &[u8] = b"http/1.1";
+
+// Serves HTTP/1.1 and HTTP/2 (also h2c, with prior knowledge) on an accepted connection.
+// On shutdown the requests in progress are answered, then the connection is closed.
+pub async fn process_http<S>(
+    socket: S,
+    client_address: SocketAddr,
+    cache: Cache,
+    path: Arc<str>,
+    shutdown: Shutdown,
+) where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    info!("HTTP client {} connected", client_address);
+
+    let service = service_fn(move |request| {
+        let c_cache = cache.clone();
+        let c_path = path.clone();
+        async move {
+            let response = handle(request, client_address, c_cache, &c_path).await;
+            Ok::<_, Infallible>(response)
+        }
+    });
+
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let connection = builder.serve_connection(TokioIo::new(socket), service);
+    tokio::pin!(connection);
+
+    let result = tokio::select! {
+        result = connection.as_mut() => result,
+        _ = shutdown.started() => {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
+    if let Err(e) = result {
+        debug!("HTTP connection from {} closed: {}", client_address, e);
+    }
+}
+
+fn status(code: StatusCode) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::new()));
+    *response.status_mut() = code;
+    response
+}
+
+async fn handle(
+    request: Request<Incoming>,
+    client_address: SocketAddr,
+    cache: Cache,
+    path: &str,
+) -> Response<Full<Bytes>> {
+    if request.uri().path() != path {
+        return status(StatusCode::NOT_FOUND);
+    }
+
+    let query = match *request.method() {
+        Method::GET => match dns_parameter(request.uri().query().unwrap_or("")) {
+            Some(query) => query,
+            None => return status(StatusCode::BAD_REQUEST),
+        },
+        Method::POST => {
+            let content_type = request.headers().get(CONTENT_TYPE);
+            if content_type != Some(&HeaderValue::from_static(DNS_MESSAGE)) {
+                return status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            }
+            match Limited::new(request.into_body(), MAX_QUERY_SIZE)
+                .collect()
+                .await
+            {
+                Ok(body) => body.to_bytes(),
+                Err(_) => return status(StatusCode::PAYLOAD_TOO_LARGE),
+            }
+        }
+        _ => return status(StatusCode::METHOD_NOT_ALLOWED),
+    };
+
+    if query.len() < MIN_MESSAGE_SIZE || query.len() > MAX_QUERY_SIZE {
+        return status(StatusCode::BAD_REQUEST);
+    }
+
+    let message = server::process_message(query, &client_address.into(), cache).await;
+    // https://datatracker.ietf.org/doc/html/rfc8484#section-5.1
+    // the HTTP freshness is what the proxy's cache would keep the answer for: from the
+    // cache, its TTLs already count the time spent there
+    let max_age = server::cache_ttl(&message).unwrap_or(0);
+    let body = message.write(BytesMut::new()).freeze();
+
+    let mut response = Response::new(Full::new(body));
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(DNS_MESSAGE));
+    headers.insert(
+        CACHE_CONTROL,
+        HeaderValue::from_str(&format!("max-age={}", max_age)).unwrap(),
+    );
+    response
+}
+
+// The "dns" variable of GET requests, base64url encoded without padding
+fn dns_parameter(query: &str) -> Option<Bytes> {
+    let value = query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("dns="))?;
+    URL_SAFE_NO_PAD
+        .decode(value.trim_end_matches('='))
+        .ok()
+        .map(Bytes::from)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::dns::message::Message;
+    use crate::dns::MessageBytes;
+    use crate::doh::{dns_parameter, process_http, ALPN_H2, ALPN_HTTP1, DNS_MESSAGE};
+    use crate::server::tests::{cache_using, cache_with, negative, query, upstream};
+    use crate::server::Cache;
+    use crate::shutdown::Shutdown;
+    use crate::tls::tests::{client_config, settings};
+    use crate::tls::{server_config, CertificateReloader};
+    use crate::ResponseCode;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    use bytes::Bytes;
+    use http_body_util::{BodyExt, Full};
+    use hyper::Request;
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+    fn parse(body: Bytes) -> Message {
+        Message::parse(&mut MessageBytes::from_bytes(body)).unwrap()
+    }
+
+    pub async fn serve(tls: bool) -> SocketAddr {
+        serve_with(tls, cache_with(&["a.example.com"]).await).await
+    }
+
+    async fn serve_with(tls: bool, cache: Cache) -> SocketAddr {
+        let acceptor = if tls {
+            let certificates = CertificateReloader::new(settings(false)).unwrap();
+            let config = server_config(&settings(false), certificates, &[ALPN_H2, ALPN_HTTP1]);
+            Some(TlsAcceptor::from(config.unwrap()))
+        } else {
+            None
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (socket, client_address) = listener.accept().await.unwrap();
+                let path: Arc<str> = Arc::from("/dns-query");
+                match &acceptor {
+                    Some(acceptor) => {
+                        let stream = acceptor.accept(socket).await.unwrap();
+                        let shutdown = Shutdown::new();
+                        let c_cache = cache.clone();
+                        tokio::spawn(process_http(
+                            stream,
+                            client_address,
+                            c_cache,
+                            path,
+                            shutdown,
+                        ));
+                    }
+                    None => {
+                        let shutdown = Shutdown::new();
+                        let c_cache = cache.clone();
+                        tokio::spawn(process_http(
+                            socket,
+                            client_address,
+                            c_cache,
+                            path,
+                            shutdown,
+                        ));
+                    }
+                }
+            }
+        });
+        address
+    }
+
+    // minimal HTTP/1.1 client, the connection is closed after the answer
+    async fn request(address: SocketAddr, head: &str, body: &[u8]) -> (String, Vec<u8>) {
+        let mut socket = TcpStream::connect(address).await.unwrap();
+        socket.write_all(head.as_bytes()).await.unwrap();
+        socket.write_all(body).await.unwrap();
+
+        let mut response = vec![];
+        socket.read_to_end(&mut response).await.unwrap();
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8(response[..split].to_vec()).unwrap();
+        (head, response[split + 4..].to_vec())
+    }
+
+    #[test]
+    fn test_dns_parameter() {
+        assert_eq!(
+            dns_parameter("ct=x&dns=AAABAAABAAAAAAAAA3d3dwdleGFtcGxlA2NvbQAAAQAB").unwrap()[..],
+            b"\x00\x00\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x03www\x07example\x03com\x00\x00\x01\x00\x01"[..]
+        );
+        assert!(dns_parameter("name=example.com").is_none());
+        assert!(dns_parameter("dns=***").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_get() {
+        let address = serve(false).await;
+        let encoded = URL_SAFE_NO_PAD.encode(query(0, "a.example.com"));
+        let head = format!(
+            "GET /dns-query?dns={} HTTP/1.1\r\nHost: localhost\r\nAccept: {}\r\nConnection: close\r\n\r\n",
+            encoded, DNS_MESSAGE
+        );
+
+        let (head, body) = request(address, &head, b"").await;
+        assert!(head.starts_with("HTTP/1.1 200"));
+        assert!(head.contains("content-type: application/dns-message"));
+        assert!(head.contains("cache-control: max-age=60"));
+
+        let m = parse(body.into());
+        assert_eq!(m.header.id, 0);
+        assert_eq!(m.answer.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_negative_max_age() {
+        // a SOA with a TTL of 300 but a MINIMUM of 60
+        let response = negative("nx.example.com", ResponseCode::NameError, 300);
+        let (upstream, _queries) = upstream(response).await;
+        let cache = cache_using(format!("tcp://{}", upstream).parse().unwrap());
+        let address = serve_with(false, cache).await;
+        let encoded = URL_SAFE_NO_PAD.encode(query(0, "nx.example.com"));
+        let head = format!(
+            "GET /dns-query?dns={} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            encoded
+        );
+
+        // from the upstream, then from the cache
+        for _ in 0..2 {
+            let (head, _) = request(address, &head, b"").await;
+            assert!(head.contains("cache-control: max-age=60"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_post() {
+        let address = serve(false).await;
+        let q = query(0, "a.example.com");
+        let head = format!(
+            "POST /dns-query HTTP/1.1\r\nHost: localhost\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            DNS_MESSAGE,
+            q.len()
+        );
+
+        let (head, body) = request(address, &head, &q).await;
+        assert!(head.starts_with("HTTP/1.1 200"));
+        assert_eq!(parse(body.into()).answer.len(), 1);
+
+        let head = "POST /dns-query HTTP/1.1\r\nHost: localhost\r\nContent-Type: text/plain\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+        let (head, _) = request(address, head, b"").await;
+        assert!(head.starts_with("HTTP/1.1 415"));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_requests() {
+        let address = serve(false).await;
+
+        let head = "GET /other?dns=AAAA HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+        assert!(request(address, head, b"")
+            .await
+            .0
+            .starts_with("HTTP/1.1 404"));
+
+        let head =
+            "GET /dns-query?dns=AAAA HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+        assert!(request(address, head, b"")
+            .await
+            .0
+            .starts_with("HTTP/1.1 400"));
+
+        let head = "PUT /dns-query HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+        assert!(request(address, head, b"")
+            .await
+            .0
+            .starts_with("HTTP/1.1 405"));
+    }
+
+    #[tokio::test]
+    async fn test_http2_over_tls() {
+        let address = serve(true).await;
+
+        let socket = TcpStream::connect(address).await.unwrap();
+        let name = ServerName::try_from("localhost").unwrap();
+        let stream = TlsConnector::from(client_config(false, ALPN_H2))
+            .connect(name, socket)
+            .await
+            .unwrap();
+        let (mut sender, connection) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                .await
+                .unwrap();
+        tokio::spawn(connection);
+
+        // several queries share the same connection
+        for name in ["a.example.com", "a.example.com"] {
+            let request = Request::post("https://localhost/dns-query")
+                .header("content-type", DNS_MESSAGE)
+                .body(Full::new(query(0, name)))
+                .unwrap();
+            let response = sender.send_request(request).await.unwrap();
+            assert_eq!(response.status(), 200);
+            assert_eq!(response.headers()["cache-control"], "max-age=60");
+
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(parse(body).answer.len(), 1);
+        }
+    }
+}
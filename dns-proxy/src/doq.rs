@@ -0,0 +1,216 @@
+use crate::dns::header::Header;
+use crate::dns::MessageBytes;
+use crate::framing;
+use crate::server::{self, Cache};
+use crate::shutdown::Shutdown;
+use bytes::BytesMut;
+use log::{debug, info, warn};
+use quinn::crypto::rustls::QuicServerConfig;
+use quinn::{Connection, Endpoint, EndpointConfig, IdleTimeout, RecvStream, SendStream, VarInt};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_rustls::rustls::ServerConfig;
+
+// https://datatracker.ietf.org/doc/html/rfc9250
+
+pub const ALPN_DOQ: &[u8] = b"doq";
+
+// https://datatracker.ietf.org/doc/html/rfc9250#section-4.3
+const DOQ_NO_ERROR: u32 = 0x0;
+const DOQ_PROTOCOL_ERROR: u32 = 0x2;
+
+// Time allowed to tell the clients the connections are closed
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Maximum number of queries (streams) open at the same time on one connection
+const MAX_CONCURRENT_STREAMS: u32 = 100;
+
+pub fn endpoint(
+    socket: std::net::UdpSocket,
+    tls_config: Arc<ServerConfig>,
+    idle_timeout: Duration,
+) -> io::Result<Endpoint> {
+    let crypto = QuicServerConfig::try_from(tls_config)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let mut transport = quinn::TransportConfig::default();
+    transport
+        .max_concurrent_bidi_streams(VarInt::from_u32(MAX_CONCURRENT_STREAMS))
+        // DoQ uses only client initiated bidirectional streams
+        .max_concurrent_uni_streams(VarInt::from_u32(0))
+        .max_idle_timeout(IdleTimeout::try_from(idle_timeout).ok());
+
+    let mut config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    config.transport_config(Arc::new(transport));
+
+    Endpoint::new(
+        EndpointConfig::default(),
+        Some(config),
+        socket,
+        Arc::new(quinn::TokioRuntime),
+    )
+}
+
+// Every query arrives on its own bidirectional stream, so a slow answer never
+// holds back the others (no head-of-line blocking as with TCP)
+// On shutdown no new stream is accepted, the connection is closed by `close`
+pub async fn process_quic(
+    connection: Connection,
+    client_address: SocketAddr,
+    cache: Cache,
+    shutdown: Shutdown,
+) {
+    info!("QUIC client {} connected", client_address);
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = connection.accept_bi() => accepted,
+            _ = shutdown.started() => return,
+        };
+        let (send, recv) = match accepted {
+            Ok(streams) => streams,
+            Err(e) => {
+                debug!("QUIC connection from {} closed: {}", client_address, e);
+                return;
+            }
+        };
+
+        let c_connection = connection.clone();
+        let c_cache = cache.clone();
+        shutdown.spawn(async move {
+            let result = process_stream(send, recv, client_address, &c_connection, c_cache).await;
+            if let Err(e) = result {
+                warn!("Error serving {}: {}", client_address, e);
+            }
+        });
+    }
+}
+
+// Closes every connection of the endpoint once the queries in flight are answered
+pub async fn close(endpoint: &Endpoint) {
+    endpoint.close(VarInt::from_u32(DOQ_NO_ERROR), b"");
+    let _ = tokio::time::timeout(CLOSE_TIMEOUT, endpoint.wait_idle()).await;
+}
+
+async fn process_stream(
+    mut send: SendStream,
+    mut recv: RecvStream,
+    client_address: SocketAddr,
+    connection: &Connection,
+    cache: Cache,
+) -> io::Result<()> {
+    let query = framing::read_message(&mut recv, framing::MAX_QUERY_SIZE).await?;
+
+    // https://datatracker.ietf.org/doc/html/rfc9250#section-4.2.1
+    // the message ID must be 0, since streams already identify the queries
+    let header = Header::parse(&mut MessageBytes::from_bytes(query.clone()))?;
+    if header.id != 0 {
+        connection.close(
+            VarInt::from_u32(DOQ_PROTOCOL_ERROR),
+            b"message ID must be 0",
+        );
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid message ID {}", header.id),
+        ));
+    }
+
+    let response = server::process_message(query, &client_address.into(), cache)
+        .await
+        .write(BytesMut::new())
+        .freeze();
+
+    framing::write_message(&mut send, &response).await?;
+    send.finish()
+        .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dns::message::Message;
+    use crate::dns::MessageBytes;
+    use crate::doq::{endpoint, process_quic, ALPN_DOQ};
+    use crate::framing;
+    use crate::server::tests::{cache_with, query};
+    use crate::shutdown::Shutdown;
+    use crate::tls::tests::{client_config, settings};
+    use crate::tls::{server_config, CertificateReloader};
+    use quinn::crypto::rustls::QuicClientConfig;
+    use quinn::{ClientConfig, Connection, Endpoint};
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    async fn serve() -> SocketAddr {
+        let certificates = CertificateReloader::new(settings(false)).unwrap();
+        let tls_config = server_config(&settings(false), certificates, &[ALPN_DOQ]).unwrap();
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = endpoint(socket, tls_config, Duration::from_secs(5)).unwrap();
+        let address = server.local_addr().unwrap();
+        let cache = cache_with(&["a.example.com", "b.example.com"]).await;
+
+        tokio::spawn(async move {
+            while let Some(incoming) = server.accept().await {
+                let connection = incoming.await.unwrap();
+                let client_address = connection.remote_address();
+                let shutdown = Shutdown::new();
+                tokio::spawn(process_quic(
+                    connection,
+                    client_address,
+                    cache.clone(),
+                    shutdown,
+                ));
+            }
+        });
+        address
+    }
+
+    async fn connect(address: SocketAddr) -> Connection {
+        let mut client = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        let crypto = QuicClientConfig::try_from(client_config(false, ALPN_DOQ)).unwrap();
+        client.set_default_client_config(ClientConfig::new(Arc::new(crypto)));
+        client.connect(address, "localhost").unwrap().await.unwrap()
+    }
+
+    async fn ask(connection: &Connection, id: u16, name: &str) -> std::io::Result<Message> {
+        let (mut send, mut recv) = connection.open_bi().await?;
+        framing::write_message(&mut send, &query(id, name)).await?;
+        send.finish().unwrap();
+
+        let response = framing::read_message(&mut recv, u16::MAX as usize).await?;
+        Message::parse(&mut MessageBytes::from_bytes(response))
+    }
+
+    #[tokio::test]
+    async fn test_doq_queries_on_streams() {
+        let address = serve().await;
+        let connection = connect(address).await;
+
+        // concurrent queries on the same connection
+        let (a, b) = tokio::join!(
+            ask(&connection, 0, "a.example.com"),
+            ask(&connection, 0, "b.example.com")
+        );
+        let (a, b) = (a.unwrap(), b.unwrap());
+        assert_eq!(a.header.id, 0);
+        assert_eq!(a.answer[0].domain_name, a.question[0].domain_name);
+        assert_eq!(b.answer[0].domain_name, b.question[0].domain_name);
+        assert_ne!(a.question[0], b.question[0]);
+    }
+
+    #[tokio::test]
+    async fn test_doq_rejects_message_id() {
+        let address = serve().await;
+        let connection = connect(address).await;
+
+        assert!(ask(&connection, 1234, "a.example.com").await.is_err());
+        let reason = connection.closed().await;
+        assert!(matches!(
+            reason,
+            quinn::ConnectionError::ApplicationClosed(ref c) if c.error_code == 2u32.into()
+        ));
+    }
+}
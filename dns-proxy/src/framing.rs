@@ -0,0 +1,165 @@
+use bytes::Bytes;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+
+// https://datatracker.ietf.org/doc/html/rfc1035#section-4.2.2
+// Over stream transports every message is prefixed with its length as two bytes
+
+// Smallest valid message: just the header
+pub(crate) const MIN_MESSAGE_SIZE: usize = 12;
+
+// Largest query accepted from clients, enough for EDNS options and padding
+pub const MAX_QUERY_SIZE: usize = 4096;
+
+// Time allowed to receive a message body after its length prefix arrived
+pub const BODY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Reads the body announced by an already received length prefix. The body may be
+// split across several TCP segments (or TLS records), so it is read until complete.
+pub async fn read_body<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    size: usize,
+    max_size: usize,
+) -> io::Result<Bytes> {
+    if !(MIN_MESSAGE_SIZE..=max_size).contains(&size) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid message size {}", size),
+        ));
+    }
+
+    let mut buffer = vec![0u8; size];
+    match timeout(BODY_TIMEOUT, reader.read_exact(&mut buffer)).await {
+        Ok(result) => result?,
+        Err(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Timeout reading message body",
+            ))
+        }
+    };
+    Ok(Bytes::from(buffer))
+}
+
+// The length prefix of the next message, read so that waiting for it can be cancelled
+// (e.g. by an idle timeout) and resumed: the byte already received is kept, instead
+// of being lost and leaving the stream out of step
+#[derive(Default)]
+pub struct LengthPrefix {
+    bytes: [u8; 2],
+    filled: usize,
+}
+
+impl LengthPrefix {
+    pub async fn read<R: AsyncRead + Unpin>(&mut self, reader: &mut R) -> io::Result<usize> {
+        while self.filled < self.bytes.len() {
+            // AsyncReadExt::read is cancel safe: nothing is read when it is dropped
+            match reader.read(&mut self.bytes[self.filled..]).await? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => self.filled += n,
+            }
+        }
+        self.filled = 0;
+        Ok(u16::from_be_bytes(self.bytes) as usize)
+    }
+}
+
+pub async fn read_message<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_size: usize,
+) -> io::Result<Bytes> {
+    let size = reader.read_u16().await? as usize;
+    read_body(reader, size, max_size).await
+}
+
+pub async fn write_message<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &[u8],
+) -> io::Result<()> {
+    if message.len() > u16::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Message too big: {}", message.len()),
+        ));
+    }
+    // a single write, so the prefix and the body usually travel together
+    let mut buffer = Vec::with_capacity(message.len() + 2);
+    buffer.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    buffer.extend_from_slice(message);
+    writer.write_all(&buffer).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::framing::{read_message, write_message, LengthPrefix, MAX_QUERY_SIZE};
+    use std::io;
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn test_message_split_in_pieces() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let message: Vec<u8> = (0..100u8).collect();
+
+        let reader = tokio::spawn(async move { read_message(&mut server, MAX_QUERY_SIZE).await });
+
+        client.write_all(&[0, 100]).await.unwrap();
+        for chunk in message.chunks(7) {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            client.write_all(chunk).await.unwrap();
+        }
+
+        assert_eq!(reader.await.unwrap().unwrap(), message);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_sizes() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+
+        client.write_all(&[0x10, 0x01]).await.unwrap();
+        let e = read_message(&mut server, MAX_QUERY_SIZE).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        client.write_all(&[0, 3, 1, 2, 3]).await.unwrap();
+        let e = read_message(&mut server, MAX_QUERY_SIZE).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_slow_body() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+
+        // the prefix arrives, but only part of the body
+        client.write_all(&[0, 20, 1, 2, 3]).await.unwrap();
+        let e = read_message(&mut server, MAX_QUERY_SIZE).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_length_prefix_cancelled() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let mut prefix = LengthPrefix::default();
+
+        // the first byte, then a timeout before the second one
+        client.write_all(&[0x01]).await.unwrap();
+        let read = tokio::time::timeout(Duration::from_secs(1), prefix.read(&mut server)).await;
+        assert!(read.is_err());
+
+        client.write_all(&[0x02]).await.unwrap();
+        assert_eq!(prefix.read(&mut server).await.unwrap(), 0x0102);
+
+        client.write_all(&[0, 12]).await.unwrap();
+        assert_eq!(prefix.read(&mut server).await.unwrap(), 12);
+    }
+
+    #[tokio::test]
+    async fn test_write_message() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let message: Vec<u8> = (0..30u8).collect();
+
+        write_message(&mut client, &message).await.unwrap();
+        assert_eq!(read_message(&mut server, 30).await.unwrap(), message);
+    }
+}
@@ -1,87 +1,655 @@
 extern crate core;
 
+mod activation;
+mod config;
 mod dns;
+mod doh;
+mod doq;
+mod framing;
 mod logger;
+mod network;
+mod proxy_protocol;
+mod resolver;
+mod router;
 mod server;
-
-use std::env;
+mod shutdown;
+mod socket;
+mod tls;
+mod upstream;
+mod upstream_doh;
+mod upstream_tls;
+mod upstream_udp;
 
 use bytes::Bytes;
-use log::{debug, info};
+use log::{debug, info, warn};
 use std::io;
-use std::net::Ipv4Addr;
-use std::str::FromStr;
-use tokio::fs;
-use tokio::net::{TcpListener, UdpSocket};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream, UdpSocket, UnixDatagram, UnixListener};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tokio_rustls::TlsAcceptor;
 
+use crate::activation::{InheritedSockets, PassedFds};
+use crate::config::Config;
 use crate::dns::header::*;
 use crate::dns::question::Question;
-use crate::server::Cache;
+use crate::network::Network;
+use crate::resolver::Resolver;
+use crate::router::Router;
+use crate::server::{Cache, ClientAddress};
+use crate::shutdown::Shutdown;
 
-async fn loop_tcp(address: (Ipv4Addr, u16), cache: Cache) -> io::Result<()> {
-    let tcp_listener = TcpListener::bind(address).await?;
+// The real client address, when a trusted load balancer sends it with the PROXY protocol
+async fn real_address(
+    socket: &mut TcpStream,
+    peer_address: SocketAddr,
+    trusted: &[Network],
+) -> Option<SocketAddr> {
+    match proxy_protocol::client_address(socket, peer_address, trusted).await {
+        Ok(address) => Some(address),
+        Err(e) => {
+            warn!("Invalid PROXY protocol header from {}: {}", peer_address, e);
+            None
+        }
+    }
+}
 
-    info!("TCP server listening on {}:{}", address.0, address.1);
+async fn loop_tcp(
+    tcp_listener: TcpListener,
+    cache: Cache,
+    idle_timeout: Duration,
+    trusted: Arc<[Network]>,
+    shutdown: Shutdown,
+) -> io::Result<()> {
+    info!("TCP server listening on {}", tcp_listener.local_addr()?);
 
     loop {
-        let (socket, client_address) = tcp_listener.accept().await?;
+        let (mut socket, peer_address) = tokio::select! {
+            accepted = tcp_listener.accept() => accepted?,
+            _ = shutdown.started() => return Ok(()),
+        };
         let c_cache = cache.clone();
-        tokio::spawn(async move {
-            server::process_tcp(socket, client_address, c_cache)
-                .await
-                .expect("Error");
+        let c_trusted = trusted.clone();
+        let c_shutdown = shutdown.clone();
+        shutdown.spawn(async move {
+            let client_address = match real_address(&mut socket, peer_address, &c_trusted).await {
+                Some(address) => address,
+                None => return,
+            };
+            let c_address = ClientAddress::from(client_address);
+            if let Err(e) =
+                server::process_tcp(socket, c_address, c_cache, idle_timeout, c_shutdown).await
+            {
+                warn!("Error serving {}: {}", client_address, e);
+            }
         });
     }
 }
 
-async fn loop_udp(address: (Ipv4Addr, u16), cache: Cache) -> io::Result<()> {
-    let socket = UdpSocket::bind(&address).await?;
+async fn loop_tls(
+    tcp_listener: TcpListener,
+    acceptor: TlsAcceptor,
+    cache: Cache,
+    idle_timeout: Duration,
+    trusted: Arc<[Network]>,
+    shutdown: Shutdown,
+) -> io::Result<()> {
+    info!("DoT server listening on {}", tcp_listener.local_addr()?);
 
-    info!("UDP server listening on {}:{}", address.0, address.1);
+    loop {
+        let (mut socket, peer_address) = tokio::select! {
+            accepted = tcp_listener.accept() => accepted?,
+            _ = shutdown.started() => return Ok(()),
+        };
+        let c_acceptor = acceptor.clone();
+        let c_cache = cache.clone();
+        let c_trusted = trusted.clone();
+        let c_shutdown = shutdown.clone();
+        shutdown.spawn(async move {
+            // the header comes before the TLS handshake
+            let client_address = match real_address(&mut socket, peer_address, &c_trusted).await {
+                Some(address) => address,
+                None => return,
+            };
+            let stream = match tls::accept(&c_acceptor, socket, client_address).await {
+                Some(stream) => stream,
+                None => return,
+            };
+            let c_address = ClientAddress::from(client_address);
+            if let Err(e) =
+                server::process_tcp(stream, c_address, c_cache, idle_timeout, c_shutdown).await
+            {
+                warn!("Error serving {}: {}", client_address, e);
+            }
+        });
+    }
+}
+
+async fn loop_doh(
+    tcp_listener: TcpListener,
+    acceptor: Option<TlsAcceptor>,
+    cache: Cache,
+    path: Arc<str>,
+    shutdown: Shutdown,
+) -> io::Result<()> {
+    info!(
+        "DoH server listening on {} ({})",
+        tcp_listener.local_addr()?,
+        if acceptor.is_some() { "https" } else { "http" }
+    );
 
     loop {
-        // let mut buffer = BytesMut::with_capacity(512);
-        let mut buffer = vec![0u8; 512];
-        let (size, client_address) = socket.recv_from(&mut buffer).await?;
+        let (socket, client_address) = tokio::select! {
+            accepted = tcp_listener.accept() => accepted?,
+            _ = shutdown.started() => return Ok(()),
+        };
+        let c_acceptor = acceptor.clone();
+        let c_cache = cache.clone();
+        let c_path = path.clone();
+        let c_shutdown = shutdown.clone();
+        shutdown.spawn(async move {
+            match c_acceptor {
+                Some(acceptor) => {
+                    if let Some(stream) = tls::accept(&acceptor, socket, client_address).await {
+                        doh::process_http(stream, client_address, c_cache, c_path, c_shutdown)
+                            .await;
+                    }
+                }
+                None => {
+                    doh::process_http(socket, client_address, c_cache, c_path, c_shutdown).await
+                }
+            }
+        });
+    }
+}
+
+async fn loop_doq(endpoint: quinn::Endpoint, cache: Cache, shutdown: Shutdown) -> io::Result<()> {
+    info!("DoQ server listening on {}", endpoint.local_addr()?);
+
+    loop {
+        let incoming = tokio::select! {
+            incoming = endpoint.accept() => match incoming {
+                Some(incoming) => incoming,
+                None => return Ok(()),
+            },
+            _ = shutdown.started() => return Ok(()),
+        };
+        let c_cache = cache.clone();
+        let c_shutdown = shutdown.clone();
+        shutdown.spawn(async move {
+            let client_address = incoming.remote_address();
+            match incoming.await {
+                Ok(connection) => {
+                    doq::process_quic(connection, client_address, c_cache, c_shutdown).await
+                }
+                Err(e) => warn!("QUIC handshake with {} failed: {}", client_address, e),
+            }
+        });
+    }
+}
+
+async fn loop_udp(
+    socket: UdpSocket,
+    cache: Cache,
+    max_in_flight: usize,
+    shutdown: Shutdown,
+) -> io::Result<()> {
+    let socket = Arc::new(socket);
+    let in_flight = Arc::new(Semaphore::new(max_in_flight));
+
+    info!("UDP server listening on {}", socket.local_addr()?);
+
+    loop {
+        // stop reading from the socket while the limit is reached, letting the
+        // kernel buffer (and eventually drop) datagrams instead of piling up tasks
+        let permit = tokio::select! {
+            permit = in_flight.clone().acquire_owned() => permit.expect("Semaphore closed"),
+            _ = shutdown.started() => return Ok(()),
+        };
+
+        // queries with an OPT record may be larger than 512 bytes, as the streams
+        let mut buffer = vec![0u8; framing::MAX_QUERY_SIZE];
+        let (size, client_address) = tokio::select! {
+            received = socket.recv_from(&mut buffer) => received?,
+            _ = shutdown.started() => return Ok(()),
+        };
 
         debug!("udp pack size {}", size);
-        if size == 0 {
+        if size < framing::MIN_MESSAGE_SIZE {
+            debug!(
+                "Ignoring datagram of {} bytes from {}",
+                size, client_address
+            );
             continue;
         }
+        buffer.truncate(size);
+
+        let c_socket = socket.clone();
+        let c_cache = cache.clone();
+        shutdown.spawn(async move {
+            let c_address = ClientAddress::from(client_address);
+            let result = server::process_bytes(Bytes::from(buffer), &c_address, c_cache).await;
+
+            if let Err(e) = c_socket.send_to(&result, client_address).await {
+                warn!("Error answering {}: {}", client_address, e);
+            }
+            drop(permit);
+        });
+    }
+}
+
+async fn loop_unix_stream(
+    listener: UnixListener,
+    cache: Cache,
+    idle_timeout: Duration,
+    shutdown: Shutdown,
+) -> io::Result<()> {
+    info!(
+        "Unix stream server listening on {:?}",
+        listener.local_addr()?
+    );
+
+    loop {
+        let (socket, address) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = shutdown.started() => return Ok(()),
+        };
+        let client_address = ClientAddress::Unix(address.as_pathname().map(PathBuf::from));
+        let c_cache = cache.clone();
+        let c_shutdown = shutdown.clone();
+        shutdown.spawn(async move {
+            let c_address = client_address.clone();
+            let result =
+                server::process_tcp(socket, c_address, c_cache, idle_timeout, c_shutdown).await;
+            if let Err(e) = result {
+                warn!("Error serving {}: {}", client_address, e);
+            }
+        });
+    }
+}
+
+async fn loop_unix_datagram(
+    socket: UnixDatagram,
+    cache: Cache,
+    max_in_flight: usize,
+    shutdown: Shutdown,
+) -> io::Result<()> {
+    let socket = Arc::new(socket);
+    let in_flight = Arc::new(Semaphore::new(max_in_flight));
 
-        // due to the way udp sockets work, concurrent requests need to be implemented in a different way (e.g. FuturesUnordered)
-        let result = server::process_bytes(Bytes::from(buffer), cache.clone()).await;
+    info!(
+        "Unix datagram server listening on {:?}",
+        socket.local_addr()?
+    );
+
+    loop {
+        let permit = tokio::select! {
+            permit = in_flight.clone().acquire_owned() => permit.expect("Semaphore closed"),
+            _ = shutdown.started() => return Ok(()),
+        };
 
-        socket.send_to(&result, client_address).await?;
+        let mut buffer = vec![0u8; framing::MAX_QUERY_SIZE];
+        let (size, address) = tokio::select! {
+            received = socket.recv_from(&mut buffer) => received?,
+            _ = shutdown.started() => return Ok(()),
+        };
+
+        // answers can only be sent to clients bound to a path
+        let client_path = match address.as_pathname() {
+            Some(path) if size >= framing::MIN_MESSAGE_SIZE => PathBuf::from(path),
+            _ => {
+                debug!("Ignoring datagram of {} bytes from {:?}", size, address);
+                continue;
+            }
+        };
+        buffer.truncate(size);
+
+        let c_socket = socket.clone();
+        let c_cache = cache.clone();
+        shutdown.spawn(async move {
+            let c_address = ClientAddress::Unix(Some(client_path.clone()));
+            let result = server::process_bytes(Bytes::from(buffer), &c_address, c_cache).await;
+
+            if let Err(e) = c_socket.send_to(&result, &client_path).await {
+                warn!("Error answering {}: {}", client_path.display(), e);
+            }
+            drop(permit);
+        });
     }
 }
 
-#[tokio::main]
-async fn main() -> io::Result<()> {
+fn main() -> io::Result<()> {
+    // still single-threaded: see PassedFds::take_from_env
+    let passed = PassedFds::take_from_env()?;
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(passed))
+}
+
+async fn run(passed: PassedFds) -> io::Result<()> {
     logger::setup_logger().expect("Error setting log");
 
-    let upstream_name =
-        env::var("DOT_SERVER_NAME").expect("Need to set DOT_SERVER_NAME");
-    let upstream_address =
-        env::var("DOT_SERVER_ADDRESS").expect("Need to set DOT_SERVER_ADDRESS");
-    // let certificate = env::var("CERTIFICATE").expect("Need to set CERTIFICATE (PEM)");
-    // let certificate_contents = fs::read_to_string(certificate).await?;
-    let cache = Cache::new(100, upstream_address, upstream_name);
-
-    let ip = "0.0.0.0";
-    let port = u16::from_str(&env::var("PORT").unwrap_or(String::from("53"))).unwrap();
-    let address: (Ipv4Addr, u16) = (ip.parse().unwrap(), port);
-
-    let c_cache1 = cache.clone();
-    let c_cache2 = cache.clone();
-    tokio::join!(
-        async move {
-            loop_tcp(address, c_cache1).await.expect("Error TCP");
-        },
-        async move {
-            loop_udp(address, c_cache2).await.expect("Error UDP");
+    let config = Config::from_env(passed.passes_sockets())?;
+    let resolver = |upstreams| {
+        Resolver::new(
+            upstreams,
+            config.upstream_strategy,
+            config.hedge_delay,
+            config.upstream_timeouts,
+            config.upstream_retries,
+        )
+    };
+    let routes = config
+        .routes
+        .into_iter()
+        .map(|route| Ok((route.domain, resolver(route.upstreams)?)))
+        .collect::<io::Result<_>>()?;
+    let router = Arc::new(Router::new(resolver(config.upstreams)?, routes));
+    router.watch(config.health_check_interval);
+    router.clone().report_on_signal()?;
+    let cache = Cache::new(100, router);
+
+    let mut inherited = InheritedSockets::from_fds(passed)?;
+    let mut tcp_listeners = socket::bind_all_tcp(&config.tcp_addresses)?;
+    tcp_listeners.append(&mut inherited.tcp);
+    let mut udp_sockets = socket::bind_all_udp(&config.udp_addresses, config.udp_sockets)?;
+    udp_sockets.append(&mut inherited.udp);
+    let mut dot_listeners = socket::bind_all_tcp(&config.dot_addresses)?;
+    dot_listeners.append(&mut inherited.dot);
+    // a QUIC connection must keep reaching the same endpoint, so never SO_REUSEPORT
+    let mut doq_sockets = socket::bind_all_udp(&config.doq_addresses, 1)?;
+    doq_sockets.append(&mut inherited.doq);
+    let mut doh_listeners = socket::bind_all_tcp(&config.doh_addresses)?;
+    doh_listeners.append(&mut inherited.doh);
+    let mut unix_listeners = inherited.unix_stream;
+    if let Some(path) = &config.unix_stream_path {
+        unix_listeners.push(socket::bind_unix_stream(path, config.unix_socket_mode)?);
+    }
+    let mut unix_sockets = inherited.unix_datagram;
+    if let Some(path) = &config.unix_datagram_path {
+        unix_sockets.push(socket::bind_unix_datagram(path, config.unix_socket_mode)?);
+    }
+
+    let certificates = match &config.tls {
+        Some(settings) => {
+            let certificates = tls::CertificateReloader::new(settings.clone())?;
+            certificates.clone().watch();
+            Some(certificates)
+        }
+        None if !dot_listeners.is_empty() || !doq_sockets.is_empty() => {
+            return Err(config::invalid(String::from(
+                "DoT and DoQ sockets need TLS_CERTIFICATE and TLS_PRIVATE_KEY",
+            )));
+        }
+        None if !doh_listeners.is_empty() && !config.doh_plaintext => {
+            return Err(config::invalid(String::from(
+                "DoH sockets need TLS_CERTIFICATE and TLS_PRIVATE_KEY, or DOH_PLAINTEXT=true",
+            )));
+        }
+        None => None,
+    };
+
+    let shutdown = Shutdown::new();
+    let mut servers = JoinSet::new();
+    let mut doq_endpoints = vec![];
+    let trusted: Arc<[Network]> = Arc::from(config.proxy_protocol_trusted.as_slice());
+    for listener in tcp_listeners {
+        servers.spawn(loop_tcp(
+            listener,
+            cache.clone(),
+            config.tcp_idle_timeout,
+            trusted.clone(),
+            shutdown.clone(),
+        ));
+    }
+    for socket in udp_sockets {
+        servers.spawn(loop_udp(
+            socket,
+            cache.clone(),
+            config.udp_max_in_flight,
+            shutdown.clone(),
+        ));
+    }
+    for listener in unix_listeners {
+        servers.spawn(loop_unix_stream(
+            listener,
+            cache.clone(),
+            config.tcp_idle_timeout,
+            shutdown.clone(),
+        ));
+    }
+    for socket in unix_sockets {
+        servers.spawn(loop_unix_datagram(
+            socket,
+            cache.clone(),
+            config.udp_max_in_flight,
+            shutdown.clone(),
+        ));
+    }
+
+    if let (Some(settings), Some(certificates)) = (&config.tls, &certificates) {
+        let dot_config = tls::server_config(settings, certificates.clone(), &[tls::ALPN_DOT])?;
+        for listener in dot_listeners {
+            let acceptor = TlsAcceptor::from(dot_config.clone());
+            servers.spawn(loop_tls(
+                listener,
+                acceptor,
+                cache.clone(),
+                config.tcp_idle_timeout,
+                trusted.clone(),
+                shutdown.clone(),
+            ));
+        }
+
+        let doq_config = tls::server_config(settings, certificates.clone(), &[doq::ALPN_DOQ])?;
+        for socket in doq_sockets {
+            let endpoint = doq::endpoint(
+                socket.into_std()?,
+                doq_config.clone(),
+                config.tcp_idle_timeout,
+            )?;
+            doq_endpoints.push(endpoint.clone());
+            servers.spawn(loop_doq(endpoint, cache.clone(), shutdown.clone()));
         }
+    }
+
+    let doh_acceptor = match (&config.tls, &certificates) {
+        (Some(settings), Some(certificates)) if !config.doh_plaintext => {
+            let alpn = [doh::ALPN_H2, doh::ALPN_HTTP1];
+            let doh_config = tls::server_config(settings, certificates.clone(), &alpn)?;
+            Some(TlsAcceptor::from(doh_config))
+        }
+        _ => None,
+    };
+    let doh_path: Arc<str> = Arc::from(config.doh_path.as_str());
+    for listener in doh_listeners {
+        servers.spawn(loop_doh(
+            listener,
+            doh_acceptor.clone(),
+            cache.clone(),
+            doh_path.clone(),
+            shutdown.clone(),
+        ));
+    }
+
+    if servers.is_empty() {
+        return Err(config::invalid(String::from("No socket to listen on")));
+    }
+
+    // runs until a signal, unless a listener fails first
+    let signal = tokio::select! {
+        signal = shutdown::signal_received() => signal?,
+        Some(result) = servers.join_next() => {
+            result??;
+            return Err(io::Error::other("Listener stopped"));
+        }
+    };
+
+    info!(
+        "{} received, finishing the queries in flight (up to {:?})",
+        signal, config.shutdown_grace_period
     );
+    // the grace period also covers the listeners stopping
+    let deadline = Instant::now() + config.shutdown_grace_period;
+    shutdown.start();
+    let stopped = tokio::time::timeout_at(deadline, async {
+        while let Some(result) = servers.join_next().await {
+            result??;
+        }
+        Ok::<_, io::Error>(())
+    })
+    .await;
+    match stopped {
+        Ok(result) => result?,
+        Err(_) => servers.abort_all(),
+    }
+    if !shutdown
+        .drain(deadline.saturating_duration_since(Instant::now()))
+        .await
+    {
+        warn!(
+            "Grace period expired, dropping {} connections or queries",
+            shutdown.in_flight()
+        );
+    }
 
+    for endpoint in doq_endpoints {
+        doq::close(&endpoint).await;
+    }
+    cache.close().await;
+    info!("Shutdown complete");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::dns::message::Message;
+    use crate::dns::MessageBytes;
+    use crate::server::tests::{cache_using, cache_with, query};
+    use crate::shutdown::Shutdown;
+    use crate::{framing, loop_udp, loop_unix_datagram, loop_unix_stream, socket};
+    use bytes::Bytes;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::Duration;
+    use tokio::net::{TcpListener, UdpSocket, UnixDatagram, UnixStream};
+
+    fn answer_count(answer: Bytes) -> u16 {
+        Message::parse(&mut MessageBytes::from_bytes(answer))
+            .unwrap()
+            .header
+            .answer_count
+    }
+
+    #[tokio::test]
+    async fn test_unix_servers() {
+        let directory = tempfile::tempdir().unwrap();
+        let stream_path = directory.path().join("dns.sock");
+        let datagram_path = directory.path().join("dns-dgram.sock");
+        let shutdown = Shutdown::new();
+
+        let listener = socket::bind_unix_stream(&stream_path, 0o600).unwrap();
+        let socket = socket::bind_unix_datagram(&datagram_path, 0o640).unwrap();
+        for (path, mode) in [(&stream_path, 0o600), (&datagram_path, 0o640)] {
+            let metadata = fs::metadata(path).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o777, mode);
+        }
+        let stream = tokio::spawn(loop_unix_stream(
+            listener,
+            cache_with(&["a.example.com"]).await,
+            Duration::from_secs(10),
+            shutdown.clone(),
+        ));
+        let datagram = tokio::spawn(loop_unix_datagram(
+            socket,
+            cache_with(&["a.example.com"]).await,
+            4,
+            shutdown.clone(),
+        ));
+
+        let mut client = UnixStream::connect(&stream_path).await.unwrap();
+        framing::write_message(&mut client, &query(1, "a.example.com"))
+            .await
+            .unwrap();
+        let answer = framing::read_message(&mut client, 4096).await.unwrap();
+        assert_eq!(answer_count(answer), 1);
+
+        // answers are sent to the path of the client
+        let client = UnixDatagram::bind(directory.path().join("client.sock")).unwrap();
+        client
+            .send_to(&query(2, "a.example.com"), &datagram_path)
+            .await
+            .unwrap();
+        let mut buffer = vec![0u8; 512];
+        let size = client.recv(&mut buffer).await.unwrap();
+        assert_eq!(answer_count(Bytes::copy_from_slice(&buffer[..size])), 1);
+
+        shutdown.start();
+        stream.await.unwrap().unwrap();
+        datagram.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_udp_datagram_sizes() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = socket.local_addr().unwrap();
+        let shutdown = Shutdown::new();
+        let cache = cache_with(&["a.example.com"]).await;
+        let listener = tokio::spawn(loop_udp(socket, cache, 4, shutdown.clone()));
+
+        // shorter than a header: ignored
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(b"\x00\x03\x01", server).await.unwrap();
+
+        // with 600 bytes of padding (RFC 7830) in its OPT record
+        let mut large = query(3, "a.example.com").to_vec();
+        large[11] = 1;
+        large.extend_from_slice(b"\x00\x00\x29\x10\x00\x00\x00\x00\x00\x02\x5c\x00\x0c\x02\x58");
+        large.resize(large.len() + 600, 0);
+        client.send_to(&large, server).await.unwrap();
+
+        let mut buffer = vec![0u8; 4096];
+        let size = client.recv(&mut buffer).await.unwrap();
+        let answer = Message::parse(&mut MessageBytes::from_bytes(Bytes::copy_from_slice(
+            &buffer[..size],
+        )))
+        .unwrap();
+        assert_eq!(answer.header.id, 3);
+        assert_eq!(answer.header.answer_count, 1);
+
+        shutdown.start();
+        listener.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_udp_shutdown_at_limit() {
+        // an upstream that never answers, so the only query keeps its permit
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = upstream.local_addr().unwrap();
+        let cache = cache_using(format!("tcp://{}", address).parse().unwrap());
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = socket.local_addr().unwrap();
+        let shutdown = Shutdown::new();
+        let listener = tokio::spawn(loop_udp(socket, cache, 1, shutdown.clone()));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client
+            .send_to(&query(1, "a.example.com"), server)
+            .await
+            .unwrap();
+        let _ = upstream.accept().await.unwrap();
+
+        shutdown.start();
+        tokio::time::timeout(Duration::from_secs(1), listener)
+            .await
+            .expect("listener stuck at the limit")
+            .unwrap()
+            .unwrap();
+    }
+}
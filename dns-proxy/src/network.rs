@@ -0,0 +1,108 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+// An address or a CIDR range, e.g. "10.0.0.0/8" or "fd00::1"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Network {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // clients of dual-stack listeners show up as IPv4-mapped IPv6 addresses
+        match (self.address, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+
+    // https://datatracker.ietf.org/doc/html/rfc1035#section-3.5
+    // https://datatracker.ietf.org/doc/html/rfc3596#section-2.5
+    // The reverse zone of the network, e.g. "168.192.in-addr.arpa" for 192.168.0.0/16.
+    // Only prefixes on a label boundary (octets, nibbles for IPv6) have one.
+    pub fn reverse_zone(&self) -> Option<String> {
+        let (mut labels, suffix): (Vec<String>, _) = match self.address {
+            IpAddr::V4(address) if self.prefix.is_multiple_of(8) => {
+                let octets = address.octets();
+                let labels = octets[..self.prefix as usize / 8].iter();
+                (labels.map(|o| o.to_string()).collect(), "in-addr.arpa")
+            }
+            IpAddr::V6(address) if self.prefix.is_multiple_of(4) => {
+                let nibbles = address
+                    .octets()
+                    .into_iter()
+                    .flat_map(|o| [o >> 4, o & 0x0f]);
+                let labels = nibbles.take(self.prefix as usize / 4);
+                (labels.map(|n| format!("{:x}", n)).collect(), "ip6.arpa")
+            }
+            _ => return None,
+        };
+        labels.reverse();
+        labels.push(String::from(suffix));
+        Some(labels.join("."))
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match value.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (value, None),
+        };
+        let address = IpAddr::from_str(address).map_err(|e| e.to_string())?;
+        let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => match prefix.parse::<u8>() {
+                Ok(prefix) if prefix <= max_prefix => prefix,
+                _ => return Err(format!("invalid prefix length {}", prefix)),
+            },
+            None => max_prefix,
+        };
+        Ok(Network { address, prefix })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::network::Network;
+
+    #[test]
+    fn test_network() {
+        let network: Network = "10.0.0.0/8".parse().unwrap();
+        assert!(network.contains("10.1.2.3".parse().unwrap()));
+        assert!(network.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!network.contains("11.1.2.3".parse().unwrap()));
+
+        let network: Network = "fd00::1".parse().unwrap();
+        assert!(network.contains("fd00::1".parse().unwrap()));
+        assert!(!network.contains("fd00::2".parse().unwrap()));
+
+        let network: Network = "0.0.0.0/0".parse().unwrap();
+        assert!(network.contains("192.0.2.1".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Network>().is_err());
+        assert!("example.com".parse::<Network>().is_err());
+    }
+
+    #[test]
+    fn test_reverse_zone() {
+        let zone = |network: &str| network.parse::<Network>().unwrap().reverse_zone();
+        assert_eq!(zone("10.0.0.0/8").unwrap(), "10.in-addr.arpa");
+        assert_eq!(zone("192.168.0.0/16").unwrap(), "168.192.in-addr.arpa");
+        assert_eq!(zone("192.0.2.1").unwrap(), "1.2.0.192.in-addr.arpa");
+        assert_eq!(zone("fd00::/8").unwrap(), "d.f.ip6.arpa");
+        assert_eq!(zone("2001:db8::/32").unwrap(), "8.b.d.0.1.0.0.2.ip6.arpa");
+        assert_eq!(zone("172.16.0.0/12"), None);
+        assert_eq!(zone("fd00::/10"), None);
+    }
+}
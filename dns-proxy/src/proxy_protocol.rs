@@ -0,0 +1,217 @@
+use crate::network::Network;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::timeout;
+
+// https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+// A load balancer in front of the TCP listeners sends the address of the real client
+// at the start of the connection, before any DNS (or TLS) data
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+// The longest v1 line, "PROXY TCP6" with both addresses at their longest
+const V1_MAX_LENGTH: usize = 107;
+
+// Enough for the addresses and the usual TLVs (e.g. AWS or Azure connection IDs)
+const V2_MAX_LENGTH: usize = 1024;
+
+// Time allowed to receive the whole header
+pub const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// Address of the client of a new connection: the one sent by the load balancer when
+// it connects from a trusted source, which must then send the header
+pub async fn client_address<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    peer_address: SocketAddr,
+    trusted: &[Network],
+) -> io::Result<SocketAddr> {
+    if !trusted.iter().any(|n| n.contains(peer_address.ip())) {
+        return Ok(peer_address);
+    }
+    Ok(read_header(reader).await?.unwrap_or(peer_address))
+}
+
+// Reads the header, returning the address of the real client. None is returned
+// for connections of the load balancer itself (health checks): v2 LOCAL or v1 UNKNOWN.
+pub async fn read_header<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<SocketAddr>> {
+    match timeout(HEADER_TIMEOUT, read_any_header(reader)).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "Timeout reading PROXY protocol header",
+        )),
+    }
+}
+
+async fn read_any_header<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<SocketAddr>> {
+    // both versions are at least this long, so nothing after the header is consumed
+    let mut start = [0u8; 12];
+    reader.read_exact(&mut start).await?;
+
+    if &start == V2_SIGNATURE {
+        read_v2(reader).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(reader, &start).await
+    } else {
+        Err(invalid_data("Missing PROXY protocol header"))
+    }
+}
+
+// "PROXY TCP4 192.0.2.1 198.51.100.1 56324 53\r\n"
+async fn read_v1<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    start: &[u8],
+) -> io::Result<Option<SocketAddr>> {
+    let mut line = start.to_vec();
+    // byte by byte, the DNS messages that follow must stay in the socket
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(invalid_data("PROXY protocol v1 header too long"));
+        }
+        line.push(reader.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid_data("Invalid PROXY protocol v1 header"))?;
+    parse_v1(line).ok_or_else(|| invalid_data("Invalid PROXY protocol v1 header"))
+}
+
+fn parse_v1(line: &str) -> Option<Option<SocketAddr>> {
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Some(None),
+        ["PROXY", "TCP4", source, _, port, _] => {
+            let ip = Ipv4Addr::from_str(source).ok()?;
+            Some(Some(SocketAddr::new(ip.into(), port.parse().ok()?)))
+        }
+        ["PROXY", "TCP6", source, _, port, _] => {
+            let ip = Ipv6Addr::from_str(source).ok()?;
+            Some(Some(SocketAddr::new(ip.into(), port.parse().ok()?)))
+        }
+        _ => None,
+    }
+}
+
+async fn read_v2<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<SocketAddr>> {
+    let version_command = reader.read_u8().await?;
+    let family = reader.read_u8().await?;
+    let length = reader.read_u16().await? as usize;
+    if version_command >> 4 != 2 {
+        return Err(invalid_data("Unsupported PROXY protocol version"));
+    }
+    if length > V2_MAX_LENGTH {
+        return Err(invalid_data("PROXY protocol v2 header too long"));
+    }
+
+    // the TLVs after the addresses are not used
+    let mut data = vec![0u8; length];
+    reader.read_exact(&mut data).await?;
+
+    match version_command & 0x0f {
+        // LOCAL
+        0x0 => Ok(None),
+        // PROXY
+        0x1 => parse_v2_address(family, &data),
+        _ => Err(invalid_data("Unsupported PROXY protocol command")),
+    }
+}
+
+fn parse_v2_address(family: u8, data: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let truncated = || invalid_data("Truncated PROXY protocol v2 address");
+    match family >> 4 {
+        // AF_INET: source, destination, source port, destination port
+        0x1 => {
+            let data = data.get(..12).ok_or_else(truncated)?;
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&data[0..4]).unwrap());
+            let port = u16::from_be_bytes([data[8], data[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // AF_INET6
+        0x2 => {
+            let data = data.get(..36).ok_or_else(truncated)?;
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&data[0..16]).unwrap());
+            let port = u16::from_be_bytes([data[32], data[33]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // AF_UNSPEC or AF_UNIX: no usable client address
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proxy_protocol::{read_header, V2_SIGNATURE};
+    use std::net::SocketAddr;
+    use tokio::io::AsyncReadExt;
+
+    async fn read(header: &[u8]) -> std::io::Result<Option<SocketAddr>> {
+        let mut data = header.to_vec();
+        data.extend_from_slice(b"\x00\x1c");
+        let mut reader = &data[..];
+        let address = read_header(&mut reader).await?;
+        // the DNS message that follows is left untouched
+        assert_eq!(reader.read_u16().await.unwrap(), 28);
+        Ok(address)
+    }
+
+    #[tokio::test]
+    async fn test_v1() {
+        let a = read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 53\r\n").await;
+        assert_eq!(a.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+
+        let a = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 853\r\n").await;
+        assert_eq!(a.unwrap(), Some("[2001:db8::1]:56324".parse().unwrap()));
+
+        assert_eq!(read(b"PROXY UNKNOWN\r\n").await.unwrap(), None);
+        assert!(read(b"PROXY TCP4 192.0.2.1 56324\r\n").await.is_err());
+        assert!(
+            read(&[b"PROXY TCP4 ".as_ref(), &[b'1'; 120], b"\r\n"].concat())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_v2() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x11, 0, 15]);
+        header.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0, 53]);
+        // a TLV, ignored
+        header.extend_from_slice(&[0x04, 0, 0]);
+        let a = read(&header).await;
+        assert_eq!(a.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x21, 0, 36]);
+        header.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8]);
+        header.extend_from_slice(&[0; 11]);
+        header.push(1);
+        header.extend_from_slice(&[0; 16]);
+        header.extend_from_slice(&[0xdc, 0x04, 0, 53]);
+        let a = read(&header).await;
+        assert_eq!(a.unwrap(), Some("[2001:db8::1]:56324".parse().unwrap()));
+
+        // LOCAL, e.g. health checks of the load balancer
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(read(&header).await.unwrap(), None);
+
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x11, 0, 4, 192, 0, 2, 1]);
+        assert!(read(&header).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_missing_header() {
+        assert!(read(b"\x00\x1c\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00")
+            .await
+            .is_err());
+    }
+}
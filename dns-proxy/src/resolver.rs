@@ -0,0 +1,619 @@
+use bytes::Bytes;
+use log::{debug, info, warn};
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::upstream::{self, Timeouts, Transport, UpstreamSettings};
+
+// Several upstreams: queries go to the one picked by the strategy, and fail over to
+// the next ones. An upstream is marked down after failed queries (passive checks) and
+// back up once it answers the periodic probes (active checks).
+
+// Consecutive failures after which an upstream is marked down
+const MAX_FAILURES: u32 = 3;
+
+// "." NS, the priming query of RFC 8109: any recursive resolver can answer it
+const PROBE: &[u8] = &[0, 0, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 1];
+
+// https://datatracker.ietf.org/doc/html/rfc1035#section-4.1.1
+const SERVER_FAILURE: u8 = 2;
+
+// Delay before the first retry, when every upstream failed. It doubles for the next ones.
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+// Round-trip time counted for a failed query, at least: a refused connection is quick
+// but must not make an upstream look fast
+const FAILURE_RTT: Duration = Duration::from_secs(1);
+
+// Which upstream is tried first. The others follow in order, when it fails.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strategy {
+    // the first upstream that is up
+    Order,
+    // each upstream in turn
+    RoundRobin,
+    // at random, in proportion to the weights
+    Random,
+    // the lowest average round-trip time
+    Fastest,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "order" => Ok(Strategy::Order),
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "random" => Ok(Strategy::Random),
+            "fastest" => Ok(Strategy::Fastest),
+            _ => Err(String::from(
+                "expected order, round-robin, random or fastest",
+            )),
+        }
+    }
+}
+
+// What the strategies are based on, logged after every health check (debug level) and
+// on SIGUSR1 (see Router::report)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UpstreamStats {
+    pub address: String,
+    pub weight: u32,
+    pub down: bool,
+    pub queries: u64,
+    pub failures: u64,
+    // None until a first answer or failure
+    pub rtt: Option<Duration>,
+}
+
+impl fmt::Display for UpstreamStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}, weight {}, {} queries, {} failures, rtt ",
+            self.address,
+            if self.down { "down" } else { "up" },
+            self.weight,
+            self.queries,
+            self.failures
+        )?;
+        match self.rtt {
+            Some(rtt) => write!(f, "{:.1}ms", rtt.as_secs_f64() * 1000.0),
+            None => write!(f, "unknown"),
+        }
+    }
+}
+
+struct Upstream {
+    settings: UpstreamSettings,
+    transport: Box<dyn Transport>,
+    // consecutive ones, reset by an answer
+    failures: AtomicU32,
+    down: AtomicBool,
+    queries: AtomicU64,
+    failed_queries: AtomicU64,
+    // average round-trip time in microseconds, 0 until measured
+    rtt: AtomicU64,
+}
+
+impl Upstream {
+    fn new(settings: UpstreamSettings, transport: Box<dyn Transport>) -> Self {
+        Upstream {
+            settings,
+            transport,
+            failures: AtomicU32::new(0),
+            down: AtomicBool::new(false),
+            queries: AtomicU64::new(0),
+            failed_queries: AtomicU64::new(0),
+            rtt: AtomicU64::new(0),
+        }
+    }
+
+    fn address(&self) -> &str {
+        &self.settings.address
+    }
+
+    fn is_down(&self) -> bool {
+        self.down.load(Ordering::Relaxed)
+    }
+
+    fn rtt(&self) -> u64 {
+        self.rtt.load(Ordering::Relaxed)
+    }
+
+    // https://datatracker.ietf.org/doc/html/rfc6298#section-2
+    // Smoothed like TCP's SRTT: each sample counts for 1/8
+    fn measured(&self, rtt: Duration) {
+        let sample = (rtt.as_micros() as u64).max(1);
+        let _ = self
+            .rtt
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |average| {
+                Some(match average {
+                    0 => sample,
+                    average => (average * 7 + sample) / 8,
+                })
+            });
+    }
+
+    fn succeeded(&self, rtt: Duration) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        self.measured(rtt);
+        self.failures.store(0, Ordering::Relaxed);
+        if self.down.swap(false, Ordering::Relaxed) {
+            info!("Upstream {} is up", self.address());
+        }
+    }
+
+    fn failed(&self, rtt: Duration) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        self.failed_queries.fetch_add(1, Ordering::Relaxed);
+        self.measured(rtt.max(FAILURE_RTT));
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= MAX_FAILURES && !self.down.swap(true, Ordering::Relaxed) {
+            warn!(
+                "Upstream {} is down after {} failures",
+                self.address(),
+                failures
+            );
+        }
+    }
+}
+
+// Index of the upstream that `point` (below the sum of the weights) falls on
+fn weighted_pick(weights: &[u32], mut point: u64) -> usize {
+    for (i, weight) in weights.iter().enumerate() {
+        if point < *weight as u64 {
+            return i;
+        }
+        point -= *weight as u64;
+    }
+    weights.len() - 1
+}
+
+fn random_u32() -> u32 {
+    let mut bytes = [0u8; 4];
+    // not a secret: a failure only makes the choice less random
+    let _ = getrandom::getrandom(&mut bytes);
+    u32::from_be_bytes(bytes)
+}
+
+pub struct Resolver {
+    upstreams: Vec<Upstream>,
+    strategy: Strategy,
+    // when set, a second upstream is queried if the first one has not answered
+    // after this delay (at once with zero)
+    hedge_delay: Option<Duration>,
+    // times every upstream is tried again, when they all failed
+    retries: u32,
+    next: AtomicUsize,
+}
+
+impl Resolver {
+    pub fn new(
+        settings: Vec<UpstreamSettings>,
+        strategy: Strategy,
+        hedge_delay: Option<Duration>,
+        timeouts: Timeouts,
+        retries: u32,
+    ) -> io::Result<Self> {
+        let upstreams = settings
+            .into_iter()
+            .map(|settings| {
+                let transport = upstream::transport(settings.clone(), timeouts)?;
+                Ok(Upstream::new(settings, transport))
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Resolver {
+            upstreams,
+            strategy,
+            hedge_delay,
+            retries,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    // The upstreams that are up, the first one picked by the strategy, then the ones
+    // that are down: they may have recovered since the last probe
+    fn candidates(&self) -> Vec<&Upstream> {
+        let mut up: Vec<&Upstream> = self.upstreams.iter().filter(|u| !u.is_down()).collect();
+        if !up.is_empty() {
+            match self.strategy {
+                Strategy::Order => {}
+                Strategy::RoundRobin => {
+                    let start = self.next.fetch_add(1, Ordering::Relaxed) % up.len();
+                    up.rotate_left(start);
+                }
+                Strategy::Random => {
+                    let weights: Vec<u32> = up.iter().map(|u| u.settings.weight).collect();
+                    let total: u64 = weights.iter().map(|w| *w as u64).sum();
+                    let picked = weighted_pick(&weights, random_u32() as u64 % total);
+                    let first = up.remove(picked);
+                    up.insert(0, first);
+                }
+                // not measured yet first, so every upstream gets a measurement
+                Strategy::Fastest => up.sort_by_key(|u| u.rtt()),
+            }
+        }
+        up.extend(self.upstreams.iter().filter(|u| u.is_down()));
+        up
+    }
+
+    // Each upstream is tried, then all of them again after a backoff, up to `retries`
+    // times: a network outage may be short
+    pub async fn exchange(&self, query: &[u8]) -> io::Result<Bytes> {
+        let mut backoff = RETRY_BACKOFF;
+        for _ in 0..self.retries {
+            match self.exchange_once(query).await {
+                Ok(answer) => return Ok(answer),
+                Err(e) => debug!("Every upstream failed, retrying in {:?}: {}", backoff, e),
+            }
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+        self.exchange_once(query).await
+    }
+
+    async fn exchange_once(&self, query: &[u8]) -> io::Result<Bytes> {
+        let candidates = self.candidates();
+        let no_upstream = io::Error::new(io::ErrorKind::NotFound, "No upstream");
+        match self.hedge_delay {
+            Some(delay) if candidates.len() > 1 => self.hedged(&candidates, query, delay).await,
+            _ => self.in_turn(&candidates, query, no_upstream).await,
+        }
+    }
+
+    // A query to one upstream, counted in its statistics unless cancelled
+    async fn attempt(&self, upstream: &Upstream, query: &[u8]) -> io::Result<Bytes> {
+        let start = Instant::now();
+        match upstream.transport.exchange(query).await {
+            Ok(answer) => {
+                upstream.succeeded(start.elapsed());
+                Ok(answer)
+            }
+            Err(e) => {
+                warn!("Error querying upstream {}: {}", upstream.address(), e);
+                upstream.failed(start.elapsed());
+                Err(e)
+            }
+        }
+    }
+
+    async fn in_turn(
+        &self,
+        candidates: &[&Upstream],
+        query: &[u8],
+        mut last_error: io::Error,
+    ) -> io::Result<Bytes> {
+        for upstream in candidates {
+            match self.attempt(upstream, query).await {
+                Ok(answer) => return Ok(answer),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    // The first two candidates race: the second one starts after the delay, or as soon
+    // as the first one fails. The first answer wins, the other query is cancelled.
+    // Only answers that match the query are returned by the transports, so a forged or
+    // mismatched answer can not win.
+    async fn hedged(
+        &self,
+        candidates: &[&Upstream],
+        query: &[u8],
+        delay: Duration,
+    ) -> io::Result<Bytes> {
+        let first = self.attempt(candidates[0], query);
+        tokio::pin!(first);
+        let early = tokio::select! {
+            biased;
+            result = &mut first => Some(result),
+            _ = tokio::time::sleep(delay) => None,
+        };
+        match early {
+            Some(Ok(answer)) => return Ok(answer),
+            Some(Err(e)) => return self.in_turn(&candidates[1..], query, e).await,
+            None => debug!("Hedging with upstream {}", candidates[1].address()),
+        }
+
+        let second = self.attempt(candidates[1], query);
+        tokio::pin!(second);
+        let result = tokio::select! {
+            result = &mut first => match result {
+                Ok(answer) => Ok(answer),
+                Err(_) => second.await,
+            },
+            result = &mut second => match result {
+                Ok(answer) => Ok(answer),
+                Err(_) => first.await,
+            },
+        };
+        match result {
+            Ok(answer) => Ok(answer),
+            Err(e) => self.in_turn(&candidates[2..], query, e).await,
+        }
+    }
+
+    pub fn stats(&self) -> Vec<UpstreamStats> {
+        self.upstreams
+            .iter()
+            .map(|upstream| UpstreamStats {
+                address: String::from(upstream.address()),
+                weight: upstream.settings.weight,
+                down: upstream.is_down(),
+                queries: upstream.queries.load(Ordering::Relaxed),
+                failures: upstream.failed_queries.load(Ordering::Relaxed),
+                rtt: match upstream.rtt() {
+                    0 => None,
+                    rtt => Some(Duration::from_micros(rtt)),
+                },
+            })
+            .collect()
+    }
+
+    // Probes every upstream at the given interval, after reloading their TLS files
+    pub fn watch(self: Arc<Self>, interval: Duration) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                for upstream in &self.upstreams {
+                    match upstream.transport.reload() {
+                        Ok(true) => info!("Reloaded TLS files of upstream {}", upstream.address()),
+                        Ok(false) => {}
+                        Err(e) => warn!(
+                            "Error reloading TLS files of upstream {}: {}",
+                            upstream.address(),
+                            e
+                        ),
+                    }
+                }
+                self.check().await;
+                for stats in self.stats() {
+                    debug!("Upstream {}", stats);
+                }
+            }
+        });
+    }
+
+    // One after the other: each probe is bounded by the connect and query timeouts
+    async fn check(&self) {
+        for upstream in &self.upstreams {
+            let start = Instant::now();
+            match upstream.transport.exchange(PROBE).await {
+                Ok(answer) if answer[3] & 0x0f != SERVER_FAILURE => {
+                    upstream.succeeded(start.elapsed())
+                }
+                Ok(_) => {
+                    debug!("Upstream {} failed the probe", upstream.address());
+                    upstream.failed(start.elapsed());
+                }
+                Err(e) => {
+                    debug!("Upstream {} failed the probe: {}", upstream.address(), e);
+                    upstream.failed(start.elapsed());
+                }
+            }
+        }
+    }
+
+    // Closes the upstream connections
+    pub async fn close(&self) {
+        for upstream in &self.upstreams {
+            upstream.transport.close().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::resolver::{weighted_pick, Resolver, Strategy, UpstreamStats, MAX_FAILURES};
+    use crate::server::tests::query;
+    use crate::upstream::tests::{settings_for, upstream};
+    use crate::upstream::Timeouts;
+    use std::sync::atomic::Ordering;
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn test_failover() {
+        let (address, connections) = upstream(1, false).await;
+        let dead = settings_for("127.0.0.1:1".parse().unwrap());
+        let resolver = Resolver::new(
+            vec![dead, settings_for(address)],
+            Strategy::Order,
+            None,
+            Timeouts::default(),
+            0,
+        )
+        .unwrap();
+
+        for _ in 0..MAX_FAILURES {
+            let answer = resolver.exchange(&query(0, "example.com")).await.unwrap();
+            assert_eq!(answer[12..], query(0, "example.com")[12..]);
+        }
+        assert!(resolver.upstreams[0].is_down());
+        assert!(!resolver.upstreams[1].is_down());
+
+        // the upstream that is down is not tried anymore
+        resolver.exchange(&query(0, "example.com")).await.unwrap();
+        assert_eq!(
+            resolver.upstreams[0].failures.load(Ordering::Relaxed),
+            MAX_FAILURES
+        );
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_check() {
+        let (address, _) = upstream(1, false).await;
+        let dead = settings_for("127.0.0.1:1".parse().unwrap());
+        let resolver = Resolver::new(
+            vec![settings_for(address), dead],
+            Strategy::Order,
+            None,
+            Timeouts::default(),
+            0,
+        )
+        .unwrap();
+
+        for _ in 0..MAX_FAILURES {
+            resolver.upstreams[0].failed(Duration::ZERO);
+            resolver.check().await;
+        }
+        assert!(!resolver.upstreams[0].is_down());
+        assert!(resolver.upstreams[1].is_down());
+
+        // a probe brings an upstream that was marked down back up
+        for _ in 0..MAX_FAILURES {
+            resolver.upstreams[0].failed(Duration::ZERO);
+        }
+        assert!(resolver.upstreams[0].is_down());
+        resolver.check().await;
+        assert!(!resolver.upstreams[0].is_down());
+
+        // every query fails: the last error is returned
+        let resolver = Resolver::new(
+            vec![settings_for("127.0.0.1:1".parse().unwrap())],
+            Strategy::Order,
+            None,
+            Timeouts::default(),
+            0,
+        )
+        .unwrap();
+        assert!(resolver.exchange(&query(0, "example.com")).await.is_err());
+
+        // and tried again after 100 ms, then 200 ms
+        let resolver = Resolver::new(
+            vec![settings_for("127.0.0.1:1".parse().unwrap())],
+            Strategy::Order,
+            None,
+            Timeouts::default(),
+            2,
+        )
+        .unwrap();
+        let start = Instant::now();
+        assert!(resolver.exchange(&query(0, "example.com")).await.is_err());
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert_eq!(resolver.stats()[0].queries, 3);
+    }
+
+    fn first(resolver: &Resolver) -> String {
+        String::from(resolver.candidates()[0].address())
+    }
+
+    fn resolver(strategy: Strategy) -> Resolver {
+        let settings = (1..=3)
+            .map(|i| settings_for(format!("127.0.0.{}:853", i).parse().unwrap()))
+            .collect();
+        Resolver::new(settings, strategy, None, Timeouts::default(), 0).unwrap()
+    }
+
+    #[test]
+    fn test_stats_format() {
+        let stats = UpstreamStats {
+            address: String::from("1.1.1.1:853"),
+            weight: 2,
+            down: true,
+            queries: 10,
+            failures: 3,
+            rtt: Some(Duration::from_micros(12_345)),
+        };
+        assert_eq!(
+            stats.to_string(),
+            "1.1.1.1:853 down, weight 2, 10 queries, 3 failures, rtt 12.3ms"
+        );
+    }
+
+    #[test]
+    fn test_strategies() {
+        let order = resolver(Strategy::Order);
+        order.upstreams[0].down.store(true, Ordering::Relaxed);
+        let candidates: Vec<&str> = order.candidates().iter().map(|u| u.address()).collect();
+        assert_eq!(
+            candidates,
+            ["127.0.0.2:853", "127.0.0.3:853", "127.0.0.1:853"]
+        );
+
+        let round_robin = resolver(Strategy::RoundRobin);
+        let firsts: Vec<String> = (0..4).map(|_| first(&round_robin)).collect();
+        assert_eq!(
+            firsts,
+            [
+                "127.0.0.1:853",
+                "127.0.0.2:853",
+                "127.0.0.3:853",
+                "127.0.0.1:853"
+            ]
+        );
+
+        let fastest = resolver(Strategy::Fastest);
+        fastest.upstreams[0].succeeded(Duration::from_millis(30));
+        fastest.upstreams[1].succeeded(Duration::from_millis(10));
+        fastest.upstreams[2].failed(Duration::from_millis(5));
+        assert_eq!(first(&fastest), "127.0.0.2:853");
+        // the average moves slowly towards new samples
+        fastest.upstreams[1].succeeded(Duration::from_millis(90));
+        assert_eq!(fastest.stats()[1].rtt, Some(Duration::from_millis(20)));
+        assert_eq!(first(&fastest), "127.0.0.2:853");
+        assert_eq!(fastest.stats()[2].failures, 1);
+        assert_eq!(fastest.stats()[2].rtt, Some(Duration::from_secs(1)));
+
+        assert_eq!(weighted_pick(&[1, 3], 0), 0);
+        assert_eq!(weighted_pick(&[1, 3], 1), 1);
+        assert_eq!(weighted_pick(&[1, 3], 3), 1);
+        assert_eq!(weighted_pick(&[2, 0, 1], 2), 2);
+
+        assert!("fastest".parse::<Strategy>().is_ok());
+        assert!("slowest".parse::<Strategy>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_hedging() {
+        // waits for a second query before answering: stuck for a single one
+        let (stuck, _) = upstream(2, false).await;
+        let (fast, _) = upstream(1, false).await;
+        let dead = settings_for("127.0.0.1:1".parse().unwrap());
+        let hedged = |settings, delay| {
+            Resolver::new(
+                settings,
+                Strategy::Order,
+                Some(delay),
+                Timeouts::default(),
+                0,
+            )
+            .unwrap()
+        };
+
+        // both at once: the fast upstream wins, the stuck query is cancelled
+        let resolver = hedged(
+            vec![settings_for(stuck), settings_for(fast)],
+            Duration::ZERO,
+        );
+        let start = Instant::now();
+        resolver.exchange(&query(0, "example.com")).await.unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        let stats = resolver.stats();
+        assert_eq!((stats[0].queries, stats[1].queries), (0, 1));
+
+        // the second upstream is only queried after the delay
+        let resolver = hedged(
+            vec![settings_for(stuck), settings_for(fast)],
+            Duration::from_millis(300),
+        );
+        let start = Instant::now();
+        resolver.exchange(&query(0, "example.com")).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(300));
+
+        // or as soon as the first one fails
+        let resolver = hedged(vec![dead, settings_for(fast)], Duration::from_secs(10));
+        let start = Instant::now();
+        resolver.exchange(&query(0, "example.com")).await.unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(resolver.stats()[0].failures, 1);
+    }
+}
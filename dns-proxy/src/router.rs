@@ -0,0 +1,175 @@
+use crate::dns::dname::DomainName;
+use crate::resolver::Resolver;
+use crate::upstream::UpstreamSettings;
+use log::{debug, info};
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+
+// Conditional forwarding: the names under a domain (e.g. an internal zone, or the
+// reverse zone of a private network) are sent to their own upstreams
+pub struct Route {
+    pub domain: DomainName,
+    pub upstreams: Vec<UpstreamSettings>,
+}
+
+pub struct Router {
+    // longest domains first, so the first match is the most specific one
+    routes: Vec<(DomainName, Arc<Resolver>)>,
+    default: Arc<Resolver>,
+}
+
+// Names are compared case-insensitively, label by label
+fn is_subdomain(name: &DomainName, domain: &DomainName) -> bool {
+    let (name, domain) = (&name.labels, &domain.labels);
+    name.len() >= domain.len()
+        && name[name.len() - domain.len()..]
+            .iter()
+            .zip(domain)
+            .all(|(a, b)| a.eq_ignore_ascii_case(b))
+}
+
+impl Router {
+    pub fn new(default: Resolver, routes: Vec<(DomainName, Resolver)>) -> Self {
+        let mut routes: Vec<_> = routes
+            .into_iter()
+            .map(|(domain, resolver)| (domain, Arc::new(resolver)))
+            .collect();
+        routes.sort_by_key(|(domain, _)| std::cmp::Reverse(domain.labels.len()));
+        Router {
+            routes,
+            default: Arc::new(default),
+        }
+    }
+
+    // The resolver of the longest domain the name is part of, or the default one
+    pub fn resolver(&self, name: &DomainName) -> &Resolver {
+        for (domain, resolver) in &self.routes {
+            if is_subdomain(name, domain) {
+                debug!("{} is routed to {}", name, domain);
+                return resolver;
+            }
+        }
+        &self.default
+    }
+
+    // Health checks of every upstream, see Resolver::watch
+    pub fn watch(&self, interval: Duration) {
+        self.default.clone().watch(interval);
+        for (_, resolver) in &self.routes {
+            resolver.clone().watch(interval);
+        }
+    }
+
+    // The stats of every upstream (see UpstreamStats), one line each, with the domain
+    // of its route
+    pub fn report(&self) -> Vec<String> {
+        let default = (String::from("default"), &self.default);
+        let routes = self
+            .routes
+            .iter()
+            .map(|(domain, resolver)| (domain.to_string(), resolver));
+        std::iter::once(default)
+            .chain(routes)
+            .flat_map(|(route, resolver)| {
+                resolver
+                    .stats()
+                    .into_iter()
+                    .map(move |stats| format!("{}: upstream {}", route, stats))
+            })
+            .collect()
+    }
+
+    // Logs the report on SIGUSR1, e.g. `kill -USR1 $(pidof dns)`
+    pub fn report_on_signal(self: Arc<Self>) -> io::Result<()> {
+        let mut user_signal = signal(SignalKind::user_defined1())?;
+        tokio::spawn(async move {
+            while user_signal.recv().await.is_some() {
+                for line in self.report() {
+                    info!("{}", line);
+                }
+            }
+        });
+        Ok(())
+    }
+
+    pub async fn close(&self) {
+        self.default.close().await;
+        for (_, resolver) in &self.routes {
+            resolver.close().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dns::dname::DomainName;
+    use crate::resolver::{Resolver, Strategy};
+    use crate::router::Router;
+    use crate::upstream::{Timeouts, UpstreamSettings};
+
+    fn resolver() -> Resolver {
+        let upstream = UpstreamSettings::new(String::from("127.0.0.1:1"), String::from("invalid"));
+        Resolver::new(
+            vec![upstream],
+            Strategy::Order,
+            None,
+            Timeouts::default(),
+            0,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_longest_match() {
+        let router = Router::new(
+            resolver(),
+            vec![
+                (DomainName::parse_url("testinternal.alfa"), resolver()),
+                (DomainName::parse_url("db.testinternal.alfa"), resolver()),
+                (DomainName::parse_url("168.192.in-addr.arpa"), resolver()),
+            ],
+        );
+        let route = |name: &str| {
+            let resolver = router.resolver(&DomainName::parse_url(name));
+            if std::ptr::eq(resolver, &*router.default) {
+                return String::from("default");
+            }
+            let (domain, _) = router
+                .routes
+                .iter()
+                .find(|(_, r)| std::ptr::eq(resolver, &**r))
+                .unwrap();
+            domain.to_string()
+        };
+
+        assert_eq!(route("alfa.testinternal.alfa"), "testinternal.alfa");
+        assert_eq!(route("testinternal.alfa"), "testinternal.alfa");
+        assert_eq!(route("Alfa.TestInternal.Alfa"), "testinternal.alfa");
+        assert_eq!(
+            route("primary.db.testinternal.alfa"),
+            "db.testinternal.alfa"
+        );
+        assert_eq!(route("1.0.168.192.in-addr.arpa"), "168.192.in-addr.arpa");
+        assert_eq!(route("1.0.0.10.in-addr.arpa"), "default");
+        assert_eq!(route("xtestinternal.alfa"), "default");
+        assert_eq!(route("alfa"), "default");
+        assert_eq!(route("example.com"), "default");
+    }
+
+    #[tokio::test]
+    async fn test_report() {
+        let router = Router::new(
+            resolver(),
+            vec![(DomainName::parse_url("testinternal.alfa"), resolver())],
+        );
+        assert_eq!(
+            router.report(),
+            vec![
+                "default: upstream 127.0.0.1:1 up, weight 1, 0 queries, 0 failures, rtt unknown",
+                "testinternal.alfa: upstream 127.0.0.1:1 up, weight 1, 0 queries, 0 failures, rtt unknown",
+            ]
+        );
+    }
+}
@@ -1,161 +1,990 @@
+use crate::dns::dname::DomainName;
 use crate::dns::message::Message;
 use crate::dns::record::ResourceRecord;
-use crate::dns::MessageBytes;
+use crate::dns::{edns, MessageBytes, QType};
+use crate::framing;
+use crate::resolver::Resolver;
+use crate::router::Router;
+use crate::shutdown::Shutdown;
 use crate::{Header, Question, ResponseCode};
 use bytes::{Bytes, BytesMut};
-use log::{debug, info};
+use log::{debug, info, warn};
+use std::fmt;
 use std::io;
-use std::net::{Ipv4Addr, SocketAddr};
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::sync::RwLock;
-use tokio_native_tls::native_tls::{Certificate, TlsConnector};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, WriteHalf};
+use tokio::sync::{mpsc, RwLock, Semaphore};
+use tokio::time::{timeout, Instant};
 use ttl_cache::TtlCache;
 
-use std::env;
-use std::rc::Rc;
+// Where a query came from, mostly for logging
+#[derive(Debug, Clone)]
+pub enum ClientAddress {
+    Ip(SocketAddr),
+    // peers of Unix sockets usually have no name
+    Unix(Option<PathBuf>),
+}
+
+impl From<SocketAddr> for ClientAddress {
+    fn from(address: SocketAddr) -> Self {
+        ClientAddress::Ip(address)
+    }
+}
+
+impl fmt::Display for ClientAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientAddress::Ip(address) => write!(f, "{}", address),
+            ClientAddress::Unix(Some(path)) => write!(f, "unix:{}", path.display()),
+            ClientAddress::Unix(None) => write!(f, "unix:(unnamed)"),
+        }
+    }
+}
+
+// Maximum number of queries answered at the same time on one TCP connection
+const MAX_PIPELINED_QUERIES: usize = 32;
+
+// The responses of the upstreams to queries with a single question. They also depend
+// on the CD flag (answers not validated by the upstream, RFC 4035 section 3.2.2) and
+// the DO flag of EDNS (answers with the DNSSEC records, RFC 3225).
+#[derive(Debug, PartialEq, Hash, Eq, Clone)]
+pub struct CacheKey {
+    pub question: Question,
+    pub checking_disabled: bool,
+    pub dnssec_ok: bool,
+}
+
+impl CacheKey {
+    fn from_query(query: &Message) -> Option<Self> {
+        let [question] = query.question.as_slice() else {
+            return None;
+        };
+        let dnssec_ok = query
+            .additional_records
+            .iter()
+            .any(|r| r.resource_type == QType::OPT && r.ttl & 0x8000 != 0);
+        Some(CacheKey {
+            question: question.clone(),
+            checking_disabled: query.header.z & 0b001 != 0,
+            dnssec_ok,
+        })
+    }
+}
+
+// A response as the upstream sent it, with when and for how long (see cache_ttl)
+#[derive(Clone)]
+struct Cached {
+    response: Message,
+    stored: Instant,
+    ttl: u64,
+}
+
+impl Cached {
+    // https://datatracker.ietf.org/doc/html/rfc1035#section-7.4
+    // The TTLs count down from when the response was stored, and none outlives the
+    // entry (e.g. a SOA whose MINIMUM is lower than its TTL)
+    fn aged(self) -> Message {
+        let elapsed = self.stored.elapsed().as_secs();
+        let mut response = self.response;
+        for record in response
+            .answer
+            .iter_mut()
+            .chain(response.authority.iter_mut())
+            .chain(response.additional_records.iter_mut())
+            .filter(|r| r.resource_type != QType::OPT)
+        {
+            record.ttl = (record.ttl as u64).min(self.ttl).saturating_sub(elapsed) as u32;
+        }
+        response
+    }
+}
 
 #[derive(Clone)]
 pub struct Cache {
-    address: String,
-    servername: String,
-    tlscontext: Arc<tokio_native_tls::TlsConnector>,
-    answers: Arc<RwLock<TtlCache<Question, Vec<ResourceRecord>>>>,
+    router: Arc<Router>,
+    answers: Arc<RwLock<TtlCache<CacheKey, Cached>>>,
 }
 
 impl Cache {
-    pub fn new(size: usize, address: String, servername:String) -> Self {
+    pub fn new(size: usize, router: Arc<Router>) -> Self {
         Cache {
-            address,
-            servername,
-            tlscontext: Arc::new(tokio_native_tls::TlsConnector::from(
-                TlsConnector::builder()
-                    // .add_root_certificate(Certificate::from_pem(&certificate).unwrap())
-                    .build()
-                    .expect("Error building certificate"),
-            )),
+            router,
             answers: Arc::new(RwLock::new(TtlCache::new(size))),
         }
     }
 
-    pub async fn get_entry(&self, question: Question) -> Vec<ResourceRecord> {
-        let lock = self.answers.read().await;
-        let value = lock.get(&question);
+    // Closes the upstream connections
+    pub async fn close(&self) {
+        self.router.close().await;
+    }
 
-        match value {
-            Some(x) => x.to_vec(),
-            None => {
-                let tlsconn = self.tlscontext.clone();
-                let new_value = get_from_tls(self.address.clone(), self.servername.clone(), tlsconn, question.clone())
-                    .await
-                    .expect("Error downloading data");
-                if new_value.len() > 0 {
-                    let ttl = (&new_value)[0].ttl as u64;
-                    drop(lock);
-                    let mut lock = self.answers.write().await;
-                    lock.insert(question, new_value.clone(), Duration::from_secs(ttl));
-                }
-                new_value
+    // The response of the upstreams to the query (`buffer`, parsed as `query`)
+    pub async fn get_entry(&self, buffer: &[u8], query: &Message) -> io::Result<Message> {
+        let key = CacheKey::from_query(query);
+
+        // the read lock must not be held while waiting for the upstream, otherwise a
+        // slow miss blocks every writer (and, behind it, every other reader)
+        if let Some(key) = &key {
+            if let Some(cached) = self.answers.read().await.get(key).cloned() {
+                return Ok(cached.aged());
             }
         }
+
+        // several questions go together to the upstreams of the first one
+        let name = match query.question.first() {
+            Some(question) => question.domain_name.clone(),
+            None => DomainName::empty(),
+        };
+        let resolver = self.router.resolver(&name);
+        let response = get_from_upstream(resolver, buffer).await?;
+        if let (Some(key), Some(ttl)) = (key, cache_ttl(&response)) {
+            let cached = Cached {
+                response: response.clone(),
+                stored: Instant::now(),
+                ttl,
+            };
+            let mut lock = self.answers.write().await;
+            lock.insert(key, cached, Duration::from_secs(ttl));
+        }
+        Ok(response)
     }
 }
 
-pub async fn process_bytes(buffer: Bytes, cache: Cache) -> Bytes {
-    debug!("{:?}", &buffer);
-    let mut packet = MessageBytes::from_bytes(buffer);
-    let m = Message::parse(&mut packet);
-    debug!("{:?}", m);
+// https://datatracker.ietf.org/doc/html/rfc2308#section-5
+// Answers are kept for the smallest TTL of their records. NXDOMAIN and NODATA answers
+// for the TTL of the SOA record of their authority section, capped by its MINIMUM
+// field, and not at all without one. Other errors (SERVFAIL, REFUSED...) are not
+// cached, so the next query asks the upstream again.
+pub fn cache_ttl(response: &Message) -> Option<u64> {
+    let ttl = match response.header.response_code {
+        ResponseCode::NoError if !response.answer.is_empty() => {
+            response.answer.iter().map(|r| r.ttl).min()?
+        }
+        ResponseCode::NoError | ResponseCode::NameError => {
+            let soa = response
+                .authority
+                .iter()
+                .find(|record| record.resource_type == QType::SOA)?;
+            // MINIMUM is the last field of the RDATA
+            let minimum = soa.resource_data.len().checked_sub(4)?;
+            let minimum = u32::from_be_bytes(soa.resource_data[minimum..].try_into().ok()?);
+            soa.ttl.min(minimum)
+        }
+        _ => return None,
+    };
+    (ttl > 0).then_some(ttl as u64)
+}
+
+// https://datatracker.ietf.org/doc/html/rfc1035#section-4.2.1
+// Datagram answers fit in 512 bytes, or in the UDP payload size of the client's OPT
+// record (RFC 6891 section 6.2.5)
+const MIN_UDP_PAYLOAD: usize = 512;
 
-    let mut answer = vec![];
-    for q in m.question.clone() {
-        debug!("Getting answer for {:?}", q);
-        let mut vrr = cache.get_entry(q).await;
-        answer.append(&mut vrr);
+// The answer to a datagram query. A bigger one than the client accepts is sent with
+// the TC flag and without its records but the OPT one (RFC 2181 section 9), so the
+// client asks again over TCP.
+pub async fn process_bytes(buffer: Bytes, client_address: &ClientAddress, cache: Cache) -> Bytes {
+    let mut message = process_message(buffer, client_address, cache).await;
+    let is_opt = |r: &ResourceRecord| r.resource_type == QType::OPT;
+    let payload = message
+        .additional_records
+        .iter()
+        .find(|r| is_opt(r))
+        .map_or(MIN_UDP_PAYLOAD, |opt| {
+            (opt.resource_class as usize).max(MIN_UDP_PAYLOAD)
+        });
+
+    let bytes = message.write(BytesMut::new());
+    if bytes.len() <= payload {
+        return bytes.freeze();
     }
+    debug!(
+        "Truncating an answer of {} bytes to {}",
+        bytes.len(),
+        payload
+    );
+    message.header.truncation = true;
+    message.answer.clear();
+    message.authority.clear();
+    message.additional_records.retain(is_opt);
+    set_counts(&mut message);
+    message.write(BytesMut::new()).freeze()
+}
+
+pub async fn process_message(
+    buffer: Bytes,
+    client_address: &ClientAddress,
+    cache: Cache,
+) -> Message {
+    let mut message = answer(buffer, client_address, cache).await;
+
+    // RFC 7828: the keepalive option is only meaningful over TCP
+    edns::set_option(&mut message, edns::TCP_KEEPALIVE, None);
+
+    message
+}
+
+// The client address is the real one, also behind a load balancer (PROXY protocol)
+async fn answer(buffer: Bytes, client_address: &ClientAddress, cache: Cache) -> Message {
+    debug!("{:?}", &buffer);
+    let mut packet = MessageBytes::from_bytes(buffer.clone());
+    let m = match Message::parse(&mut packet) {
+        Ok(m) => m,
+        Err(e) => {
+            warn!("Invalid query from {}: {}", client_address, e);
+            return format_error(buffer);
+        }
+    };
+    debug!("Query from {}: {:?}", client_address, m);
 
-    Message {
+    // the sections, flags (AA, RA, AD) and RCODE of the upstream, for this query
+    let mut response = match cache.get_entry(&buffer, &m).await {
+        Ok(response) => Message {
+            header: Header {
+                id: m.header.id,
+                recursion_desired: m.header.recursion_desired,
+                ..response.header
+            },
+            question: m.question.clone(),
+            ..response
+        },
+        // every upstream failed: the client may try another server
+        Err(e) => {
+            warn!("No answer from the upstreams for {}: {}", client_address, e);
+            Message {
+                header: Header {
+                    question_response: 1,
+                    response_code: ResponseCode::ServerFailure,
+                    ..m.header.clone()
+                },
+                question: m.question.clone(),
+                answer: vec![],
+                authority: vec![],
+                additional_records: vec![],
+            }
+        }
+    };
+    set_edns(&mut response, &m);
+    set_counts(&mut response);
+    response
+}
+
+// https://datatracker.ietf.org/doc/html/rfc1035#section-4.1.1
+// A query that can't be parsed gets FORMERR, with just its header (all zeros if the
+// header itself is cut)
+fn format_error(buffer: Bytes) -> Message {
+    let header = Header::parse(&mut MessageBytes::from_bytes(buffer)).unwrap_or_default();
+    let mut response = Message {
         header: Header {
             question_response: 1,
-            answer_count: answer.len() as u16,
-            ..m.header
+            truncation: false,
+            response_code: ResponseCode::FormatError,
+            ..header
         },
-        answer,
-        ..m
+        question: vec![],
+        answer: vec![],
+        authority: vec![],
+        additional_records: vec![],
+    };
+    set_counts(&mut response);
+    response
+}
+
+fn set_counts(message: &mut Message) {
+    message.header.question_count = message.question.len() as u16;
+    message.header.answer_count = message.answer.len() as u16;
+    message.header.nameserver_count = message.authority.len() as u16;
+    message.header.additional_records_count = message.additional_records.len() as u16;
+}
+
+// https://datatracker.ietf.org/doc/html/rfc6891#section-6.1.1
+// The OPT record is hop-by-hop: the response has one only when the query has, rebuilt
+// from the query's. Only the extended RCODE and flags of the upstream are kept, none
+// of its options (cookies, padding, NSID...), so the cached responses never reach
+// the clients with them. The TCP keepalive option of the client is kept, for
+// process_tcp_bytes.
+fn set_edns(response: &mut Message, query: &Message) {
+    let is_opt = |r: &ResourceRecord| r.resource_type == QType::OPT;
+    let upstream_ttl = response
+        .additional_records
+        .iter()
+        .find(|r| is_opt(r))
+        .map(|r| r.ttl);
+    response.additional_records.retain(|r| !is_opt(r));
+
+    let Some(query_opt) = query.additional_records.iter().find(|r| is_opt(r)) else {
+        return;
+    };
+    response.additional_records.push(ResourceRecord {
+        ttl: upstream_ttl.unwrap_or(query_opt.ttl),
+        data_length: 0,
+        resource_data: Bytes::new(),
+        ..query_opt.clone()
+    });
+    let keepalive = edns::find_option(query, edns::TCP_KEEPALIVE);
+    edns::set_option(response, edns::TCP_KEEPALIVE, keepalive);
+}
+
+async fn process_tcp_bytes(
+    buffer: Bytes,
+    client_address: &ClientAddress,
+    cache: Cache,
+    idle_timeout: Duration,
+) -> Bytes {
+    let mut message = answer(buffer, client_address, cache).await;
+
+    // https://datatracker.ietf.org/doc/html/rfc7828#section-3.3.2
+    // answered only to clients that asked for it, in units of 100 milliseconds
+    if edns::find_option(&message, edns::TCP_KEEPALIVE).is_some() {
+        let timeout = (idle_timeout.as_millis() / 100).min(u16::MAX as u128) as u16;
+        let data = Bytes::copy_from_slice(&timeout.to_be_bytes());
+        edns::set_option(&mut message, edns::TCP_KEEPALIVE, Some(data));
     }
-    .write(BytesMut::new())
-    .freeze()
+
+    message.write(BytesMut::new()).freeze()
 }
 
-pub async fn process_tcp(
-    mut socket: TcpStream,
-    client_address: SocketAddr,
+// https://datatracker.ietf.org/doc/html/rfc7766#section-6.2.1
+// Queries on the same connection are answered concurrently and the responses are
+// written as soon as they are ready, so they may be out of order (matched by ID).
+// The connection is closed after `idle_timeout` without new queries or pending answers,
+// or on shutdown once the pending answers are written.
+pub async fn process_tcp<S>(
+    socket: S,
+    client_address: ClientAddress,
     cache: Cache,
-) -> io::Result<()> {
+    idle_timeout: Duration,
+    shutdown: Shutdown,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
     info!("Client {} connected", client_address);
 
-    let size = socket.read_u16().await? as usize;
-    let mut buffer = BytesMut::with_capacity(size);
-    socket.read_buf(&mut buffer).await?;
+    let (mut reader, writer) = tokio::io::split(socket);
+    let (sender, receiver) = mpsc::channel::<Bytes>(MAX_PIPELINED_QUERIES);
+    let writer = tokio::spawn(write_responses(writer, receiver));
+    let in_flight = Arc::new(Semaphore::new(MAX_PIPELINED_QUERIES));
+    // one for the whole connection, as the idle timeout below cancels its reads
+    let mut prefix = framing::LengthPrefix::default();
 
-    let mut new_message = process_bytes(buffer.freeze(), cache.clone()).await;
+    loop {
+        let read = tokio::select! {
+            read = timeout(idle_timeout, prefix.read(&mut reader)) => read,
+            _ = shutdown.started() => {
+                debug!("Closing connection from {} on shutdown", client_address);
+                break;
+            }
+        };
+        let size = match read {
+            Ok(Ok(size)) => size,
+            Ok(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Ok(Err(e)) => return Err(e),
+            Err(_) if in_flight.available_permits() < MAX_PIPELINED_QUERIES => continue,
+            Err(_) => {
+                debug!("Closing idle connection from {}", client_address);
+                break;
+            }
+        };
+        let buffer = framing::read_body(&mut reader, size, framing::MAX_QUERY_SIZE).await?;
 
-    socket.write_u16(new_message.len() as u16).await?;
-    socket.write_buf(&mut new_message).await?;
+        let permit = in_flight
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Semaphore closed");
+        let c_sender = sender.clone();
+        let c_address = client_address.clone();
+        let c_cache = cache.clone();
+        tokio::spawn(async move {
+            let response = process_tcp_bytes(buffer, &c_address, c_cache, idle_timeout).await;
+            // the writer is gone only when the connection failed
+            let _ = c_sender.send(response).await;
+            drop(permit);
+        });
+    }
 
-    Ok(())
+    // the writer finishes after the answers still being processed are sent
+    drop(sender);
+    writer.await?
 }
 
-async fn get_from_tls(
-    address: String,
-    name: String,
-    tlsconn: Arc<tokio_native_tls::TlsConnector>,
-    question: Question,
-) -> std::io::Result<Vec<ResourceRecord>> {
-    let socket = TcpStream::connect(address.clone()).await?;
+async fn write_responses<W: AsyncWrite>(
+    mut writer: WriteHalf<W>,
+    mut receiver: mpsc::Receiver<Bytes>,
+) -> io::Result<()> {
+    while let Some(message) = receiver.recv().await {
+        framing::write_message(&mut writer, &message).await?;
+    }
+    writer.shutdown().await
+}
 
-    let mut socket = tlsconn
-        .connect(&name, socket)
-        .await
-        .expect("Error connecting over TLS");
+// The query of the client is forwarded as is (flags, EDNS options, every question):
+// only its ID is replaced by the upstream connection
+async fn get_from_upstream(resolver: &Resolver, query: &[u8]) -> std::io::Result<Message> {
+    let data = resolver.exchange(query).await?;
+    Message::parse(&mut MessageBytes::from_bytes(data))
+        .map_err(|e| io::Error::new(e.kind(), format!("Invalid answer: {}", e)))
+}
 
-    let msg = Message {
-        header: Header {
-            id: 123,
-            question_response: 0,
-            opcode: 0,
-            authoritative_answer: 0,
-            truncation: false,
-            recursion_desired: true,
-            recursion_available: false,
-            z: 0,
-            response_code: ResponseCode::NoError,
-            question_count: 1,
-            answer_count: 0,
-            nameserver_count: 0,
-            additional_records_count: 0,
-        },
-        question: vec![question],
-        answer: vec![],
-        authority: vec![],
-        additional_records: vec![],
+#[cfg(test)]
+pub mod tests {
+    use crate::dns::dname::DomainName;
+    use crate::dns::message::Message;
+    use crate::dns::record::ResourceRecord;
+    use crate::dns::{edns, MessageBytes, QType};
+    use crate::resolver::{Resolver, Strategy};
+    use crate::router::Router;
+    use crate::server::{
+        cache_ttl, process_bytes, process_tcp, Cache, CacheKey, Cached, ClientAddress,
+    };
+    use crate::shutdown::Shutdown;
+    use crate::upstream::{Timeouts, UpstreamSettings};
+    use crate::{framing, Header, Question, ResponseCode};
+    use bytes::{Bytes, BytesMut};
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+    use tokio::time::Instant;
+
+    fn question(name: &str) -> Question {
+        Question {
+            domain_name: DomainName::parse_url(name),
+            query_type: QType::A,
+            query_class: 1,
+        }
+    }
+
+    // a cache that sends its misses to the upstream
+    pub fn cache_using(upstream: UpstreamSettings) -> Cache {
+        let resolver = Resolver::new(
+            vec![upstream],
+            Strategy::Order,
+            None,
+            Timeouts::default(),
+            0,
+        )
+        .unwrap();
+        Cache::new(10, Arc::new(Router::new(resolver, vec![])))
+    }
+
+    // a cache that never needs the upstream for the given names
+    pub async fn cache_with(names: &[&str]) -> Cache {
+        let upstream = UpstreamSettings::new(String::from("127.0.0.1:1"), String::from("invalid"));
+        let cache = cache_using(upstream);
+        for name in names {
+            let record = ResourceRecord {
+                domain_name: DomainName::parse_url(name),
+                resource_type: QType::A,
+                resource_class: 1,
+                ttl: 60,
+                data_length: 4,
+                resource_data: Bytes::from(&b"\x7f\x00\x00\x01"[..]),
+            };
+            let response = Message {
+                header: Header {
+                    question_response: 1,
+                    recursion_available: true,
+                    response_code: ResponseCode::NoError,
+                    ..Header::default()
+                },
+                question: vec![question(name)],
+                answer: vec![record],
+                authority: vec![],
+                additional_records: vec![],
+            };
+            let key = CacheKey {
+                question: question(name),
+                checking_disabled: false,
+                dnssec_ok: false,
+            };
+            let cached = Cached {
+                response,
+                stored: Instant::now(),
+                ttl: 60,
+            };
+            cache
+                .answers
+                .write()
+                .await
+                .insert(key, cached, Duration::from_secs(60));
+        }
+        cache
     }
-    .write(BytesMut::new())
-    .freeze();
 
-    socket.write_u16(msg.len() as u16).await?;
-    socket.write_all(msg.as_ref()).await?;
+    // An upstream over plain TCP that passes on the queries it receives, and answers
+    // them with `response` (with their ID)
+    pub async fn upstream(response: Message) -> (SocketAddr, mpsc::UnboundedReceiver<Bytes>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            while let Ok(query) = framing::read_message(&mut socket, 4096).await {
+                // the ID and question of the query, as the upstream connections check
+                let m = Message::parse(&mut MessageBytes::from_bytes(query.clone())).unwrap();
+                let answer = Message {
+                    header: Header {
+                        id: m.header.id,
+                        ..response.header.clone()
+                    },
+                    question: m.question,
+                    ..response.clone()
+                }
+                .write(BytesMut::new());
+                sender.send(query).unwrap();
+                framing::write_message(&mut socket, &answer).await.unwrap();
+            }
+        });
+        (address, receiver)
+    }
+
+    pub fn query(id: u16, name: &str) -> Bytes {
+        Message {
+            header: Header {
+                id,
+                recursion_desired: true,
+                response_code: ResponseCode::NoError,
+                question_count: 1,
+                ..Header::default()
+            },
+            question: vec![question(name)],
+            answer: vec![],
+            authority: vec![],
+            additional_records: vec![],
+        }
+        .write(BytesMut::new())
+        .freeze()
+    }
+
+    async fn serve(cache: Cache, idle_timeout: Duration, shutdown: Shutdown) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (socket, client_address) = listener.accept().await.unwrap();
+            process_tcp(socket, client_address.into(), cache, idle_timeout, shutdown)
+                .await
+                .unwrap();
+        });
+        TcpStream::connect(address).await.unwrap()
+    }
+
+    async fn read_message(socket: &mut TcpStream) -> Message {
+        let size = socket.read_u16().await.unwrap() as usize;
+        let mut buffer = vec![0u8; size];
+        socket.read_exact(&mut buffer).await.unwrap();
+        Message::parse(&mut MessageBytes::from_bytes(Bytes::from(buffer))).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_format_error() {
+        let cache = cache_with(&["a.example.com"]).await;
+        let client_address = ClientAddress::Unix(None);
+        // the question is cut in the middle of its name
+        let truncated = query(7, "a.example.com").slice(..20);
+        let answer = process_bytes(truncated, &client_address, cache).await;
 
-    let response_size = socket.read_u16().await? as usize;
-    let mut data = BytesMut::with_capacity(response_size);
-    socket.read_buf(&mut data).await?;
+        let m = Message::parse(&mut MessageBytes::from_bytes(answer)).unwrap();
+        assert_eq!(m.header.id, 7);
+        assert_eq!(m.header.question_response, 1);
+        assert_eq!(m.header.response_code, ResponseCode::FormatError);
+        assert!(m.question.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_server_failure() {
+        // the upstream of cache_with refuses connections
+        let cache = cache_with(&["a.example.com"]).await;
+        let client_address = ClientAddress::Unix(None);
+        let answer = process_bytes(query(1, "b.example.com"), &client_address, cache).await;
+
+        let m = Message::parse(&mut MessageBytes::from_bytes(answer)).unwrap();
+        assert_eq!(m.header.id, 1);
+        assert_eq!(m.header.question_response, 1);
+        assert_eq!(m.header.response_code, ResponseCode::ServerFailure);
+        assert!(m.answer.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_upstream_answer() {
+        // one answer record announced, none sent
+        let mut response = negative("nx.example.com", ResponseCode::NameError, 300);
+        response.header.answer_count = 1;
+        let (address, mut queries) = upstream(response).await;
+        let cache = cache_using(format!("tcp://{}", address).parse().unwrap());
+        let client_address = ClientAddress::Unix(None);
+
+        let answer = process_bytes(query(5, "nx.example.com"), &client_address, cache).await;
+        queries.recv().await.unwrap();
+        let m = Message::parse(&mut MessageBytes::from_bytes(answer)).unwrap();
+        assert_eq!(m.header.id, 5);
+        assert_eq!(m.header.response_code, ResponseCode::ServerFailure);
+        assert!(m.authority.is_empty());
+    }
+
+    fn opt(options: &[u8]) -> ResourceRecord {
+        ResourceRecord {
+            domain_name: DomainName::empty(),
+            resource_type: QType::OPT,
+            // payload size
+            resource_class: 1232,
+            // DO
+            ttl: 0x8000,
+            data_length: options.len() as u16,
+            resource_data: Bytes::copy_from_slice(options),
+        }
+    }
+
+    // for example.com, with a MINIMUM of 60 seconds
+    fn soa(ttl: u32) -> ResourceRecord {
+        ResourceRecord {
+            domain_name: DomainName::parse_url("example.com"),
+            resource_type: QType::SOA,
+            resource_class: 1,
+            ttl,
+            data_length: 28,
+            // serial, refresh, retry, expire, minimum
+            resource_data: Bytes::from(
+                &b"\x02ns\x00\x02me\x00\0\0\0\x01\0\0\x0e\x10\0\0\x02\x58\0\x01\x51\x80\0\0\0\x3c"
+                    [..],
+            ),
+        }
+    }
+
+    fn address(ttl: u32) -> ResourceRecord {
+        ResourceRecord {
+            domain_name: DomainName::parse_url("a.example.com"),
+            resource_type: QType::A,
+            resource_class: 1,
+            ttl,
+            data_length: 4,
+            resource_data: Bytes::from(&b"\x7f\x00\x00\x01"[..]),
+        }
+    }
+
+    // an answer without records, but the SOA in the authority section
+    pub fn negative(name: &str, response_code: ResponseCode, ttl: u32) -> Message {
+        Message {
+            header: Header {
+                question_response: 1,
+                recursion_available: true,
+                response_code,
+                question_count: 1,
+                nameserver_count: 1,
+                ..Header::default()
+            },
+            question: vec![question(name)],
+            answer: vec![],
+            authority: vec![soa(ttl)],
+            additional_records: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_query() {
+        let soa = soa(300);
+        let (address, mut queries) = upstream(Message {
+            header: Header {
+                question_response: 1,
+                authoritative_answer: 1,
+                recursion_desired: true,
+                recursion_available: true,
+                // AD
+                z: 0b010,
+                response_code: ResponseCode::NameError,
+                question_count: 1,
+                nameserver_count: 1,
+                additional_records_count: 1,
+                ..Header::default()
+            },
+            question: vec![question("nx.example.com")],
+            answer: vec![],
+            authority: vec![soa.clone()],
+            // NSID, a cookie and padding, towards this proxy only
+            additional_records: vec![opt(
+                b"\x00\x03\x00\x02ns\x00\x0a\x00\x08cookie00\x00\x0c\x00\x02\x00\x00",
+            )],
+        })
+        .await;
+        let cache = cache_using(format!("tcp://{}", address).parse().unwrap());
+
+        // with the AD flag and an EDNS Client Subnet option
+        let mut query =
+            Message::parse(&mut MessageBytes::from_bytes(query(9, "nx.example.com"))).unwrap();
+        query.header.z = 0b010;
+        query.header.additional_records_count = 1;
+        query.additional_records = vec![opt(b"\x00\x08\x00\x04\x00\x01\x18\x00")];
+        let query = query.write(BytesMut::new()).freeze();
+
+        let client_address = ClientAddress::Unix(None);
+        let answer = process_bytes(query.clone(), &client_address, cache).await;
+
+        // only the ID is replaced
+        let forwarded = queries.recv().await.unwrap();
+        assert_eq!(forwarded[2..], query[2..]);
+
+        let m = Message::parse(&mut MessageBytes::from_bytes(answer)).unwrap();
+        assert_eq!(m.header.id, 9);
+        assert_eq!(m.header.authoritative_answer, 1);
+        assert!(m.header.recursion_available);
+        assert_eq!(m.header.z, 0b010);
+        assert_eq!(m.header.response_code, ResponseCode::NameError);
+        assert_eq!(m.authority, vec![soa]);
+        assert_eq!(m.additional_records, vec![opt(b"")]);
+    }
+
+    #[tokio::test]
+    async fn test_negative_caching() {
+        let client_address = ClientAddress::Unix(None);
+        for (response_code, ttl, cached) in [
+            (ResponseCode::NameError, 300, true),
+            (ResponseCode::NoError, 300, true),
+            // the SOA record expires
+            (ResponseCode::NameError, 0, false),
+            (ResponseCode::ServerFailure, 300, false),
+            (ResponseCode::Refused, 300, false),
+        ] {
+            let response = negative("nx.example.com", response_code, ttl);
+            let (address, mut queries) = upstream(response).await;
+            let cache = cache_using(format!("tcp://{}", address).parse().unwrap());
+
+            for id in [1, 2] {
+                let query = query(id, "nx.example.com");
+                let answer = process_bytes(query, &client_address, cache.clone()).await;
+                let m = Message::parse(&mut MessageBytes::from_bytes(answer)).unwrap();
+                assert_eq!(m.header.id, id);
+                assert_eq!(m.header.response_code, response_code);
+                assert!(m.answer.is_empty());
+                // from the cache, the SOA lasts no longer than its MINIMUM (60)
+                let ttl = if id == 2 && cached { ttl.min(60) } else { ttl };
+                assert_eq!(m.authority, vec![soa(ttl)]);
+            }
+            queries.recv().await.unwrap();
+            assert_eq!(queries.try_recv().is_err(), cached, "{:?}", response_code);
+        }
+    }
 
-    // println!("{:?}", data);
-    let mut pm = MessageBytes::from_bytes(data.freeze());
-    let result = Message::parse(&mut pm);
-    // println!("{:#?}", result);
+    #[tokio::test]
+    async fn test_cache_key_flags() {
+        let response = negative("nx.example.com", ResponseCode::NameError, 300);
+        let (address, mut queries) = upstream(response).await;
+        let cache = cache_using(format!("tcp://{}", address).parse().unwrap());
+        let client_address = ClientAddress::Unix(None);
 
-    Ok(result.answer)
+        let with_flags = |id: u16, checking_disabled: bool, dnssec_ok: bool| {
+            let mut query =
+                Message::parse(&mut MessageBytes::from_bytes(query(id, "nx.example.com"))).unwrap();
+            if checking_disabled {
+                query.header.z = 0b001;
+            }
+            if dnssec_ok {
+                query.header.additional_records_count = 1;
+                query.additional_records = vec![opt(b"")];
+            }
+            query.write(BytesMut::new()).freeze()
+        };
+
+        // CD, then without it: the answer the upstream did not validate is not reused
+        for (id, checking_disabled, dnssec_ok) in [
+            (1, true, false),
+            (2, false, false),
+            (3, false, true),
+            (4, false, false),
+        ] {
+            let query = with_flags(id, checking_disabled, dnssec_ok);
+            process_bytes(query, &client_address, cache.clone()).await;
+        }
+        for _ in 0..3 {
+            queries.recv().await.unwrap();
+        }
+        assert!(queries.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_types() {
+        let response = negative("nx.example.com", ResponseCode::NameError, 300);
+        let (address, mut queries) = upstream(response).await;
+        let cache = cache_using(format!("tcp://{}", address).parse().unwrap());
+        let client_address = ClientAddress::Unix(None);
+
+        // two private use types (RFC 6895), then the first again
+        for (id, query_type) in [(1, 65280), (2, 65281), (3, 65280)] {
+            let mut query =
+                Message::parse(&mut MessageBytes::from_bytes(query(id, "nx.example.com"))).unwrap();
+            query.question[0].query_type = QType::Unknown(query_type);
+            let query = query.write(BytesMut::new()).freeze();
+            let m = Message::parse(&mut MessageBytes::from_bytes(
+                process_bytes(query, &client_address, cache.clone()).await,
+            ))
+            .unwrap();
+            assert_eq!(m.question[0].query_type, QType::Unknown(query_type));
+        }
+        for _ in 0..2 {
+            queries.recv().await.unwrap();
+        }
+        assert!(queries.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_truncated_answer() {
+        // 30 addresses of 31 bytes each (the names are written uncompressed)
+        let name = "big.example.com";
+        let record = |i: u8| ResourceRecord {
+            domain_name: DomainName::parse_url(name),
+            resource_type: QType::A,
+            resource_class: 1,
+            ttl: 60,
+            data_length: 4,
+            resource_data: Bytes::copy_from_slice(&[10, 0, 0, i]),
+        };
+        let mut response = negative(name, ResponseCode::NoError, 300);
+        response.answer = (0..30).map(record).collect();
+        response.header.answer_count = 30;
+        let (address, _queries) = upstream(response).await;
+        let cache = cache_using(format!("tcp://{}", address).parse().unwrap());
+        let client_address = ClientAddress::Unix(None);
+
+        let answer = process_bytes(query(1, name), &client_address, cache.clone()).await;
+        assert!(answer.len() <= 512);
+        let m = Message::parse(&mut MessageBytes::from_bytes(answer)).unwrap();
+        assert!(m.header.truncation);
+        assert_eq!(m.header.answer_count, 0);
+        assert!(m.answer.is_empty() && m.authority.is_empty());
+        assert_eq!(m.question, vec![question(name)]);
+
+        // within the UDP payload size of the client (1232)
+        let mut query = Message::parse(&mut MessageBytes::from_bytes(query(2, name))).unwrap();
+        query.header.additional_records_count = 1;
+        query.additional_records = vec![opt(b"")];
+        let query = query.write(BytesMut::new()).freeze();
+        let m = Message::parse(&mut MessageBytes::from_bytes(
+            process_bytes(query, &client_address, cache).await,
+        ))
+        .unwrap();
+        assert!(!m.header.truncation);
+        assert_eq!(m.answer.len(), 30);
+        assert_eq!(m.additional_records, vec![opt(b"")]);
+    }
+
+    #[test]
+    fn test_cache_ttl() {
+        // capped by the MINIMUM of the SOA
+        let nxdomain = negative("nx.example.com", ResponseCode::NameError, 300);
+        assert_eq!(cache_ttl(&nxdomain), Some(60));
+        let nxdomain = negative("nx.example.com", ResponseCode::NameError, 30);
+        assert_eq!(cache_ttl(&nxdomain), Some(30));
+
+        // without a SOA
+        let mut nodata = negative("nx.example.com", ResponseCode::NoError, 300);
+        nodata.authority.clear();
+        assert_eq!(cache_ttl(&nodata), None);
+
+        // the smallest TTL of the answer
+        let mut positive = negative("a.example.com", ResponseCode::NoError, 300);
+        positive.answer = vec![address(300), address(100), address(200)];
+        assert_eq!(cache_ttl(&positive), Some(100));
+    }
+
+    #[test]
+    fn test_cached_ttls() {
+        let mut response = negative("nx.example.com", ResponseCode::NameError, 300);
+        response.answer = vec![address(100)];
+        response.additional_records = vec![opt(b"")];
+        let cached = Cached {
+            response,
+            stored: Instant::now() - Duration::from_secs(40),
+            ttl: 60,
+        };
+
+        // 40 seconds later, down from the smallest of their TTL and the entry's
+        let m = cached.clone().aged();
+        assert_eq!((m.answer[0].ttl, m.authority[0].ttl), (20, 20));
+        assert_eq!(m.additional_records, vec![opt(b"")]);
+
+        let m = Cached {
+            stored: Instant::now() - Duration::from_secs(90),
+            ..cached
+        }
+        .aged();
+        assert_eq!((m.answer[0].ttl, m.authority[0].ttl), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_tcp_pipelined_queries() {
+        let cache = cache_with(&["a.example.com", "b.example.com"]).await;
+        let mut socket = serve(cache, Duration::from_secs(5), Shutdown::new()).await;
+
+        // both queries are sent before reading any answer
+        let mut pipelined = BytesMut::new();
+        for (id, name) in [(1, "a.example.com"), (2, "b.example.com")] {
+            let q = query(id, name);
+            pipelined.extend_from_slice(&(q.len() as u16).to_be_bytes());
+            pipelined.extend_from_slice(&q);
+        }
+        socket.write_all(&pipelined).await.unwrap();
+
+        let mut ids = vec![];
+        for _ in 0..2 {
+            let m = read_message(&mut socket).await;
+            assert_eq!(m.header.question_response, 1);
+            assert_eq!(m.answer.len(), 1);
+            assert_eq!(m.answer[0].domain_name, m.question[0].domain_name);
+            ids.push(m.header.id);
+        }
+        ids.sort();
+        assert_eq!(ids, vec![1, 2]);
+
+        // the connection stays open for more queries
+        let q = query(3, "a.example.com");
+        socket.write_u16(q.len() as u16).await.unwrap();
+        socket.write_all(&q).await.unwrap();
+        assert_eq!(read_message(&mut socket).await.header.id, 3);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_idle_timeout_and_keepalive() {
+        let cache = cache_with(&["a.example.com"]).await;
+        let mut socket = serve(cache, Duration::from_millis(300), Shutdown::new()).await;
+
+        // empty edns-tcp-keepalive option in the OPT record
+        let mut q = BytesMut::from(&query(7, "a.example.com")[..]);
+        q[11] = 1;
+        q.extend_from_slice(&b"\x00\x00\x29\x10\x00\x00\x00\x00\x00\x00\x04\x00\x0b\x00\x00"[..]);
+        socket.write_u16(q.len() as u16).await.unwrap();
+        socket.write_all(&q).await.unwrap();
+
+        let m = read_message(&mut socket).await;
+        assert_eq!(m.header.id, 7);
+        assert_eq!(
+            edns::find_option(&m, edns::TCP_KEEPALIVE),
+            Some(Bytes::from(&b"\x00\x03"[..]))
+        );
+
+        // nothing else is sent, so the server closes the connection
+        let closed = tokio::time::timeout(Duration::from_secs(2), socket.read_u8()).await;
+        assert!(matches!(closed, Ok(Err(_))));
+    }
+
+    #[tokio::test]
+    async fn test_tcp_shutdown() {
+        let cache = cache_with(&["a.example.com"]).await;
+        let shutdown = Shutdown::new();
+        let mut socket = serve(cache, Duration::from_secs(60), shutdown.clone()).await;
+
+        let q = query(9, "a.example.com");
+        socket.write_u16(q.len() as u16).await.unwrap();
+        socket.write_all(&q).await.unwrap();
+        assert_eq!(read_message(&mut socket).await.header.id, 9);
+
+        // closed right away, without waiting for the idle timeout
+        shutdown.start();
+        let closed = tokio::time::timeout(Duration::from_secs(2), socket.read_u8()).await;
+        assert!(matches!(closed, Ok(Err(_))));
+    }
 }
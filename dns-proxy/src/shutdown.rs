@@ -0,0 +1,84 @@
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+// Stops the listeners and keeps track of the work they started, so the queries
+// being answered can finish before exiting (e.g. SIGTERM on a rolling update)
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    token: CancellationToken,
+    tracker: TaskTracker,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Shutdown::default()
+    }
+
+    // Spawns a task that is waited for by `drain`
+    pub fn spawn<F>(&self, task: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.tracker.spawn(task)
+    }
+
+    // Resolves once the shutdown started: stop accepting new work
+    pub async fn started(&self) {
+        self.token.cancelled().await
+    }
+
+    pub fn start(&self) {
+        self.token.cancel();
+        self.tracker.close();
+    }
+
+    // Waits for the tracked tasks, returning false when some are still running
+    // after the grace period
+    pub async fn drain(&self, grace_period: Duration) -> bool {
+        tokio::time::timeout(grace_period, self.tracker.wait())
+            .await
+            .is_ok()
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.tracker.len()
+    }
+}
+
+// SIGTERM is sent by Kubernetes and systemd, SIGINT by Ctrl+C
+pub async fn signal_received() -> io::Result<&'static str> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = terminate.recv() => Ok("SIGTERM"),
+        _ = interrupt.recv() => Ok("SIGINT"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shutdown::Shutdown;
+    use std::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_drain() {
+        let shutdown = Shutdown::new();
+        let c_shutdown = shutdown.clone();
+        shutdown.spawn(async move {
+            c_shutdown.started().await;
+            // in-flight work finishing after the shutdown started
+            tokio::time::sleep(Duration::from_secs(2)).await;
+        });
+
+        shutdown.start();
+        assert!(!shutdown.drain(Duration::from_secs(1)).await);
+        assert_eq!(shutdown.in_flight(), 1);
+        assert!(shutdown.drain(Duration::from_secs(5)).await);
+    }
+}
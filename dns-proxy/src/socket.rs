@@ -0,0 +1,234 @@
+use socket2::{Domain, Protocol, Socket, Type};
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::sync::Mutex;
+use tokio::net::{TcpListener, UdpSocket, UnixDatagram, UnixListener};
+
+// A wildcard IPv6 address accepts IPv4 clients too (dual-stack), unless the same
+// port is also bound on the IPv4 wildcard, in which case both sockets would clash
+pub fn is_dual_stack(address: &SocketAddr, all: &[SocketAddr]) -> bool {
+    address.is_ipv6()
+        && address.ip().is_unspecified()
+        && !all
+            .iter()
+            .any(|a| a.is_ipv4() && a.ip().is_unspecified() && a.port() == address.port())
+}
+
+fn new_socket(
+    address: &SocketAddr,
+    dual_stack: bool,
+    ty: Type,
+    protocol: Protocol,
+) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(*address), ty, Some(protocol))?;
+    if address.is_ipv6() {
+        socket.set_only_v6(!dual_stack)?;
+    }
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+pub fn bind_udp(address: SocketAddr, dual_stack: bool) -> io::Result<UdpSocket> {
+    let socket = new_socket(&address, dual_stack, Type::DGRAM, Protocol::UDP)?;
+    socket.bind(&address.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+// Several sockets on the same address with SO_REUSEPORT: the kernel spreads the
+// datagrams across them by client address, so each one can be read on its own core
+pub fn bind_udp_group(
+    address: SocketAddr,
+    dual_stack: bool,
+    count: usize,
+) -> io::Result<Vec<UdpSocket>> {
+    if count <= 1 {
+        return Ok(vec![bind_udp(address, dual_stack)?]);
+    }
+
+    let mut sockets: Vec<UdpSocket> = Vec::with_capacity(count);
+    for _ in 0..count {
+        // with port 0, the others join the port picked for the first one
+        let address = match sockets.first() {
+            Some(first) => first.local_addr()?,
+            None => address,
+        };
+        let socket = new_socket(&address, dual_stack, Type::DGRAM, Protocol::UDP)?;
+        socket.set_reuse_port(true)?;
+        socket.bind(&address.into())?;
+        sockets.push(UdpSocket::from_std(socket.into())?);
+    }
+    Ok(sockets)
+}
+
+pub fn bind_tcp(address: SocketAddr, dual_stack: bool) -> io::Result<TcpListener> {
+    let socket = new_socket(&address, dual_stack, Type::STREAM, Protocol::TCP)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+// Binds every address before serving, so a bad one fails the startup right away
+pub fn bind_all_tcp(addresses: &[SocketAddr]) -> io::Result<Vec<TcpListener>> {
+    addresses
+        .iter()
+        .map(|address| bind_tcp(*address, is_dual_stack(address, addresses)))
+        .collect()
+}
+
+pub fn bind_all_udp(
+    addresses: &[SocketAddr],
+    sockets_per_address: usize,
+) -> io::Result<Vec<UdpSocket>> {
+    let mut sockets = vec![];
+    for address in addresses {
+        let dual_stack = is_dual_stack(address, addresses);
+        sockets.extend(bind_udp_group(*address, dual_stack, sockets_per_address)?);
+    }
+    Ok(sockets)
+}
+
+// A socket left behind by a previous run would make bind fail, but any other
+// kind of file is kept: the path is probably wrong
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path),
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        )),
+        Err(_) => Ok(()),
+    }
+}
+
+// Access to Unix sockets is controlled by the permissions of their path. The socket is
+// created under a umask that already denies what the mode denies to the group and
+// the others: set afterwards only, it would first be reachable with the default
+// permissions. The owner keeps all of them until set_mode, as the umask applies to the
+// files the other threads create in the meantime too.
+fn bind_with_mode<T>(
+    path: &Path,
+    mode: u32,
+    bind: impl FnOnce(&Path) -> io::Result<T>,
+) -> io::Result<T> {
+    static UMASK: Mutex<()> = Mutex::new(());
+
+    remove_stale_socket(path)?;
+    let bound = {
+        let _lock = UMASK.lock().unwrap();
+        // SAFETY: umask has no memory effects, and the previous mask is restored
+        let previous = unsafe { libc::umask(!mode as libc::mode_t & 0o077) };
+        let bound = bind(path);
+        unsafe { libc::umask(previous) };
+        bound?
+    };
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    Ok(bound)
+}
+
+pub fn bind_unix_stream(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    bind_with_mode(path, mode, |path| UnixListener::bind(path))
+}
+
+pub fn bind_unix_datagram(path: &Path, mode: u32) -> io::Result<UnixDatagram> {
+    bind_with_mode(path, mode, |path| UnixDatagram::bind(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::socket::{
+        bind_tcp, bind_udp, bind_udp_group, bind_unix_datagram, bind_unix_stream, is_dual_stack,
+    };
+    use std::fs;
+    use std::net::SocketAddr;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_dual_stack() {
+        let v4: SocketAddr = "0.0.0.0:53".parse().unwrap();
+        let v6: SocketAddr = "[::]:53".parse().unwrap();
+        let v6_other: SocketAddr = "[::]:5353".parse().unwrap();
+        let v6_local: SocketAddr = "[::1]:53".parse().unwrap();
+
+        assert!(is_dual_stack(&v6, &[v6]));
+        assert!(!is_dual_stack(&v6, &[v4, v6]));
+        assert!(is_dual_stack(&v6_other, &[v4, v6_other]));
+        assert!(!is_dual_stack(&v6_local, &[v6_local]));
+        assert!(!is_dual_stack(&v4, &[v4]));
+    }
+
+    #[tokio::test]
+    async fn test_bind_both_families_on_the_same_port() {
+        let tcp = bind_tcp("0.0.0.0:0".parse().unwrap(), false).unwrap();
+        let port = tcp.local_addr().unwrap().port();
+
+        // skipped silently where IPv6 is disabled (e.g. some containers)
+        if let Ok(tcp6) = bind_tcp(SocketAddr::new("::".parse().unwrap(), port), false) {
+            assert_eq!(tcp6.local_addr().unwrap().port(), port);
+        }
+
+        let udp = bind_udp("127.0.0.1:0".parse().unwrap(), false).unwrap();
+        assert!(udp.local_addr().unwrap().is_ipv4());
+    }
+
+    #[tokio::test]
+    async fn test_bind_udp_group() {
+        let sockets = bind_udp_group("127.0.0.1:0".parse().unwrap(), false, 4).unwrap();
+        assert_eq!(sockets.len(), 4);
+        let address = sockets[0].local_addr().unwrap();
+        assert!(sockets.iter().all(|s| s.local_addr().unwrap() == address));
+
+        // without SO_REUSEPORT the address stays busy
+        assert!(bind_udp(address, false).is_err());
+
+        // every datagram reaches one of the sockets
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(b"query", address).unwrap();
+        let mut buffer = [0u8; 16];
+        let received = receive_any(&sockets, &mut buffer).await;
+        assert_eq!(&buffer[..received], b"query");
+    }
+
+    async fn receive_any(sockets: &[tokio::net::UdpSocket], buffer: &mut [u8]) -> usize {
+        loop {
+            for socket in sockets {
+                if let Ok((size, _)) = socket.try_recv_from(buffer) {
+                    return size;
+                }
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bind_unix_sockets() {
+        let directory = tempfile::tempdir().unwrap();
+        let stream = directory.path().join("dns.sock");
+        let datagram = directory.path().join("dns-dgram.sock");
+
+        let listener = bind_unix_stream(&stream, 0o660).unwrap();
+        let mode = fs::metadata(&stream).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+
+        // the file left by the previous listener is replaced
+        drop(listener);
+        bind_unix_stream(&stream, 0o600).unwrap();
+        let mode = fs::metadata(&stream).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        bind_unix_datagram(&datagram, 0o666).unwrap();
+        assert_eq!(
+            fs::metadata(&datagram).unwrap().permissions().mode() & 0o777,
+            0o666
+        );
+
+        // regular files are never removed
+        let regular = directory.path().join("regular");
+        fs::write(&regular, b"keep").unwrap();
+        assert!(bind_unix_stream(&regular, 0o660).is_err());
+        assert_eq!(fs::read(&regular).unwrap(), b"keep");
+    }
+}
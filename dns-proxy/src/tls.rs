@@ -0,0 +1,336 @@
+use log::{info, warn};
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::timeout;
+use tokio_rustls::rustls::crypto::{ring, CryptoProvider};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+// Time allowed to complete the TLS handshake of a new client
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// https://datatracker.ietf.org/doc/html/rfc7858#section-3.2
+pub const ALPN_DOT: &[u8] = b"dot";
+
+// Certificate used by the listeners that accept TLS from clients
+#[derive(Clone, Debug)]
+pub struct TlsSettings {
+    pub certificate: PathBuf,
+    pub private_key: PathBuf,
+    // when set, clients must present a certificate signed by one of these CAs
+    pub client_ca: Option<PathBuf>,
+    pub reload_interval: Duration,
+}
+
+pub fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn invalid<E: std::fmt::Display>(path: &Path, e: E) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), e),
+    )
+}
+
+pub fn load_certificates(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certificates = CertificateDer::pem_file_iter(path)
+        .map_err(|e| invalid(path, e))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| invalid(path, e))?;
+
+    if certificates.is_empty() {
+        return Err(invalid(path, "no certificate found"));
+    }
+    Ok(certificates)
+}
+
+pub fn load_private_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(|e| invalid(path, e))
+}
+
+// Serves the certificate and key from disk, reloading them when the files change
+// (e.g. renewed by cert-manager), so new handshakes pick them up without a restart
+pub struct CertificateReloader {
+    settings: TlsSettings,
+    current: RwLock<(Vec<u8>, Arc<CertifiedKey>)>,
+}
+
+impl CertificateReloader {
+    pub fn new(settings: TlsSettings) -> io::Result<Arc<Self>> {
+        let (contents, key) = CertificateReloader::load(&settings)?;
+        Ok(Arc::new(CertificateReloader {
+            settings,
+            current: RwLock::new((contents, key)),
+        }))
+    }
+
+    fn load(settings: &TlsSettings) -> io::Result<(Vec<u8>, Arc<CertifiedKey>)> {
+        let mut contents = std::fs::read(&settings.certificate)?;
+        contents.extend(std::fs::read(&settings.private_key)?);
+
+        let certificates = load_certificates(&settings.certificate)?;
+        let private_key = load_private_key(&settings.private_key)?;
+        let key = CertifiedKey::from_der(certificates, private_key, &provider())
+            .map_err(|e| invalid(&settings.private_key, e))?;
+
+        Ok((contents, Arc::new(key)))
+    }
+
+    // Returns true when a new certificate was loaded
+    pub fn reload(&self) -> io::Result<bool> {
+        let mut contents = std::fs::read(&self.settings.certificate)?;
+        contents.extend(std::fs::read(&self.settings.private_key)?);
+        if self.current.read().unwrap().0 == contents {
+            return Ok(false);
+        }
+
+        // a half written pair fails here and is retried on the next check
+        let loaded = CertificateReloader::load(&self.settings)?;
+        *self.current.write().unwrap() = loaded;
+        Ok(true)
+    }
+
+    pub fn watch(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.settings.reload_interval);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                match self.reload() {
+                    Ok(true) => info!(
+                        "Reloaded TLS certificate {}",
+                        self.settings.certificate.display()
+                    ),
+                    Ok(false) => {}
+                    Err(e) => warn!("Error reloading TLS certificate: {}", e),
+                }
+            }
+        });
+    }
+}
+
+impl std::fmt::Debug for CertificateReloader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CertificateReloader")
+            .field("settings", &self.settings)
+            .finish()
+    }
+}
+
+impl ResolvesServerCert for CertificateReloader {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().1.clone())
+    }
+}
+
+pub fn server_config(
+    settings: &TlsSettings,
+    certificates: Arc<CertificateReloader>,
+    alpn: &[&[u8]],
+) -> io::Result<Arc<ServerConfig>> {
+    let builder = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| invalid(&settings.certificate, e))?;
+
+    let builder = match &settings.client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in load_certificates(path)? {
+                roots.add(certificate).map_err(|e| invalid(path, e))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider())
+                .build()
+                .map_err(|e| invalid(path, e))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut config = builder.with_cert_resolver(certificates);
+    config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+    Ok(Arc::new(config))
+}
+
+// Handshake of a new client, failures are only logged
+pub async fn accept<IO>(
+    acceptor: &TlsAcceptor,
+    socket: IO,
+    client_address: SocketAddr,
+) -> Option<TlsStream<IO>>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(socket)).await {
+        Ok(Ok(stream)) => Some(stream),
+        Ok(Err(e)) => {
+            warn!("TLS handshake with {} failed: {}", client_address, e);
+            None
+        }
+        Err(_) => {
+            warn!("TLS handshake with {} timed out", client_address);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::dns::message::Message;
+    use crate::dns::MessageBytes;
+    use crate::framing;
+    use crate::server::process_tcp;
+    use crate::server::tests::{cache_with, query};
+    use crate::shutdown::Shutdown;
+    use crate::tls::{
+        load_certificates, load_private_key, provider, server_config, CertificateReloader,
+        TlsSettings, ALPN_DOT,
+    };
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+    pub fn fixture(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("../test-infra/certificates")
+            .join(name)
+    }
+
+    pub fn settings(client_ca: bool) -> TlsSettings {
+        TlsSettings {
+            certificate: fixture("server.crt"),
+            private_key: fixture("server.key"),
+            client_ca: if client_ca {
+                Some(fixture("ca.crt"))
+            } else {
+                None
+            },
+            reload_interval: Duration::from_secs(60),
+        }
+    }
+
+    pub fn client_config(client_certificate: bool, alpn: &[u8]) -> Arc<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+        for c in load_certificates(&fixture("ca.crt")).unwrap() {
+            roots.add(c).unwrap();
+        }
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        let mut config = if client_certificate {
+            builder
+                .with_client_auth_cert(
+                    load_certificates(&fixture("client.crt")).unwrap(),
+                    load_private_key(&fixture("client.key")).unwrap(),
+                )
+                .unwrap()
+        } else {
+            builder.with_no_client_auth()
+        };
+        config.alpn_protocols = vec![alpn.to_vec()];
+        Arc::new(config)
+    }
+
+    async fn serve_dot(settings: TlsSettings) -> std::net::SocketAddr {
+        let certificates = CertificateReloader::new(settings.clone()).unwrap();
+        let acceptor =
+            TlsAcceptor::from(server_config(&settings, certificates, &[ALPN_DOT]).unwrap());
+        let cache = cache_with(&["a.example.com"]).await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (socket, client_address) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                let cache = cache.clone();
+                tokio::spawn(async move {
+                    if let Ok(stream) = acceptor.accept(socket).await {
+                        let _ = process_tcp(
+                            stream,
+                            client_address.into(),
+                            cache,
+                            Duration::from_secs(5),
+                            Shutdown::new(),
+                        )
+                        .await;
+                    }
+                });
+            }
+        });
+        address
+    }
+
+    async fn ask(
+        address: std::net::SocketAddr,
+        config: Arc<ClientConfig>,
+    ) -> std::io::Result<Message> {
+        let socket = TcpStream::connect(address).await?;
+        let name = ServerName::try_from("localhost").unwrap();
+        let mut stream = TlsConnector::from(config).connect(name, socket).await?;
+
+        framing::write_message(&mut stream, &query(42, "a.example.com")).await?;
+        let response = framing::read_message(&mut stream, u16::MAX as usize).await?;
+        Message::parse(&mut MessageBytes::from_bytes(response))
+    }
+
+    #[tokio::test]
+    async fn test_dot_listener() {
+        let address = serve_dot(settings(false)).await;
+
+        let m = ask(address, client_config(false, ALPN_DOT)).await.unwrap();
+        assert_eq!(m.header.id, 42);
+        assert_eq!(m.answer.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_dot_listener_with_client_certificate() {
+        let address = serve_dot(settings(true)).await;
+
+        let m = ask(address, client_config(true, ALPN_DOT)).await.unwrap();
+        assert_eq!(m.header.id, 42);
+
+        // TLS 1.3 reports the rejected certificate only when reading
+        assert!(ask(address, client_config(false, ALPN_DOT)).await.is_err());
+    }
+
+    #[test]
+    fn test_reload() {
+        let directory = tempfile::tempdir().unwrap();
+        let settings = TlsSettings {
+            certificate: directory.path().join("tls.crt"),
+            private_key: directory.path().join("tls.key"),
+            client_ca: None,
+            reload_interval: Duration::from_secs(60),
+        };
+        std::fs::copy(fixture("server.crt"), &settings.certificate).unwrap();
+        std::fs::copy(fixture("server.key"), &settings.private_key).unwrap();
+
+        let reloader = CertificateReloader::new(settings.clone()).unwrap();
+        let first = reloader.current.read().unwrap().1.cert[0].clone();
+        assert!(!reloader.reload().unwrap());
+
+        // a broken pair keeps serving the previous certificate
+        std::fs::copy(fixture("client.crt"), &settings.certificate).unwrap();
+        assert!(reloader.reload().is_err());
+        assert_eq!(reloader.current.read().unwrap().1.cert[0], first);
+
+        std::fs::copy(fixture("client.key"), &settings.private_key).unwrap();
+        assert!(reloader.reload().unwrap());
+        assert_ne!(reloader.current.read().unwrap().1.cert[0], first);
+    }
+}
@@ -0,0 +1,999 @@
+use crate::framing;
+use crate::upstream_doh::DohUpstream;
+use crate::upstream_tls::{self, UpstreamTls};
+use crate::upstream_udp::UdpUpstream;
+use bytes::{Bytes, BytesMut};
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+// https://datatracker.ietf.org/doc/html/rfc7858#section-3.4
+// https://datatracker.ietf.org/doc/html/rfc7766#section-6.2.1
+// Connections to the upstream (DoT or plain TCP) are kept open and shared: queries
+// are pipelined and the answers, possibly out of order, are matched by message ID
+
+// Queries sent on a connection before another one is opened
+const MAX_PIPELINED_QUERIES: usize = 100;
+
+// Connections opened to the upstream at most
+const MAX_CONNECTIONS: usize = 4;
+
+// Times allowed to the upstreams: an unreachable one must fail quickly, so another
+// one can be tried
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timeouts {
+    // TCP connection
+    pub connect: Duration,
+    // TLS handshake, and HTTP/2 one for DoH
+    pub handshake: Duration,
+    // an answer
+    pub query: Duration,
+    // a connection without queries is closed
+    pub idle: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            connect: Duration::from_secs(2),
+            handshake: Duration::from_secs(3),
+            query: Duration::from_secs(5),
+            idle: Duration::from_secs(30),
+        }
+    }
+}
+
+// The result of the future, or a TimedOut error with this message
+pub async fn within<T>(
+    duration: Duration,
+    future: impl Future<Output = io::Result<T>>,
+    message: impl FnOnce() -> String,
+) -> io::Result<T> {
+    match timeout(duration, future).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, message())),
+    }
+}
+
+// Upstreams close idle connections, possibly while a query is being sent on one: it
+// fails with ConnectionAborted (see closed) and is sent once more, on a new one. The
+// connections are kept behind an async mutex held while connecting, so concurrent
+// queries wait for the new connection instead of opening one each. `send` gets true
+// for the retry, when the closed connection may still have to be dropped.
+pub async fn retry_closed<T, F>(mut send: impl FnMut(bool) -> F) -> io::Result<T>
+where
+    F: Future<Output = io::Result<T>>,
+{
+    match send(false).await {
+        Err(e) if e.kind() == io::ErrorKind::ConnectionAborted => {
+            debug!("Retrying on a new upstream connection: {}", e);
+            send(true).await
+        }
+        result => result,
+    }
+}
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+// How the queries reach an upstream, chosen by the scheme of its URL
+pub trait Transport: Send + Sync {
+    // Sends a query (its ID may be replaced) and returns the answer, which is checked
+    // to match the query
+    fn exchange<'a>(&'a self, query: &'a [u8]) -> BoxFuture<'a, io::Result<Bytes>>;
+
+    // Picks up new TLS files, for the next connections
+    fn reload(&self) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    // Closes the connections, e.g. on shutdown
+    fn close(&self) -> BoxFuture<'_, ()>;
+}
+
+pub fn transport(settings: UpstreamSettings, timeouts: Timeouts) -> io::Result<Box<dyn Transport>> {
+    Ok(match settings.scheme {
+        Scheme::Tls | Scheme::Tcp => Box::new(UpstreamPool::new(settings, timeouts)?),
+        Scheme::Https => Box::new(DohUpstream::new(settings, timeouts)?),
+        Scheme::Udp => Box::new(UdpUpstream::new(settings, timeouts)?),
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scheme {
+    // DNS over TLS, RFC 7858
+    Tls,
+    // DNS over HTTPS, RFC 8484
+    Https,
+    Udp,
+    Tcp,
+}
+
+impl Scheme {
+    fn default_port(self) -> u16 {
+        match self {
+            Scheme::Tls => 853,
+            Scheme::Https => 443,
+            Scheme::Udp | Scheme::Tcp => 53,
+        }
+    }
+
+    pub fn uses_tls(self) -> bool {
+        matches!(self, Scheme::Tls | Scheme::Https)
+    }
+}
+
+// An upstream server, e.g. "tls://1.1.1.1:853?name=one.one.one.one",
+// "https://dns.google/dns-query" or "udp://10.0.0.2"
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UpstreamSettings {
+    pub scheme: Scheme,
+    // host:port, resolved when connecting
+    pub address: String,
+    // path of the DoH requests
+    pub path: String,
+    // validated against the certificate
+    pub name: String,
+    // share of the queries with the weighted random strategy
+    pub weight: u32,
+    // trusted instead of the system CAs, e.g. a private CA
+    pub ca: Option<PathBuf>,
+    // SHA-256 digests of the accepted public keys (SPKI)
+    pub pins: Vec<Vec<u8>>,
+    // no certificate validation, for testing (pins are still checked)
+    pub insecure: bool,
+    // presented to upstreams that require client authentication: a PEM certificate
+    // chain and PKCS#8 key, or a PKCS#12 archive
+    pub client_certificate: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+    pub client_pkcs12: Option<PathBuf>,
+    pub client_pkcs12_password: String,
+}
+
+impl UpstreamSettings {
+    pub fn new(address: String, name: String) -> Self {
+        UpstreamSettings {
+            scheme: Scheme::Tls,
+            address,
+            path: String::from("/dns-query"),
+            name,
+            weight: 1,
+            ca: None,
+            pins: vec![],
+            insecure: false,
+            client_certificate: None,
+            client_key: None,
+            client_pkcs12: None,
+            client_pkcs12_password: String::new(),
+        }
+    }
+}
+
+impl FromStr for UpstreamSettings {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let scheme = match value.split_once("://") {
+            Some(("tls", _)) => Scheme::Tls,
+            Some(("https", _)) => Scheme::Https,
+            Some(("udp", _)) => Scheme::Udp,
+            Some(("tcp", _)) => Scheme::Tcp,
+            _ => {
+                return Err(String::from(
+                    "expected a tls://, https://, udp:// or tcp:// URL",
+                ))
+            }
+        };
+        let rest = value.split_once("://").unwrap().1;
+        let (location, options) = rest.split_once('?').unwrap_or((rest, ""));
+        let (authority, path) = match location.find('/') {
+            Some(i) => location.split_at(i),
+            None => (location, ""),
+        };
+        let (host, port) = split_host_port(authority, scheme.default_port())?;
+        let address = if host.contains(':') {
+            format!("[{}]:{}", host, port)
+        } else {
+            format!("{}:{}", host, port)
+        };
+        let mut settings = UpstreamSettings::new(address, String::new());
+        settings.scheme = scheme;
+        match (scheme, path) {
+            (_, "") => {}
+            (Scheme::Https, path) => settings.path = String::from(path),
+            _ => return Err(format!("unexpected path {}", path)),
+        }
+
+        let mut name = None;
+        for option in options.split('&').filter(|o| !o.is_empty()) {
+            match option.split_once('=') {
+                Some(("name", value)) if !value.is_empty() => name = Some(String::from(value)),
+                Some(("weight", value)) => match value.parse() {
+                    Ok(weight) if weight > 0 => settings.weight = weight,
+                    _ => return Err(format!("invalid weight {}", value)),
+                },
+                Some(("ca", value)) if !value.is_empty() => settings.ca = Some(value.into()),
+                Some(("pin", value)) => settings.pins.push(upstream_tls::parse_pin(value)?),
+                Some(("insecure", "true")) => settings.insecure = true,
+                Some(("insecure", "false")) => settings.insecure = false,
+                Some(("cert", value)) if !value.is_empty() => {
+                    settings.client_certificate = Some(value.into())
+                }
+                Some(("key", value)) if !value.is_empty() => {
+                    settings.client_key = Some(value.into())
+                }
+                Some(("pkcs12", value)) if !value.is_empty() => {
+                    settings.client_pkcs12 = Some(value.into())
+                }
+                Some(("password", value)) => settings.client_pkcs12_password = value.into(),
+                _ => return Err(format!("unknown option {}", option)),
+            }
+        }
+        match (
+            &settings.client_certificate,
+            &settings.client_key,
+            &settings.client_pkcs12,
+        ) {
+            (Some(_), None, _) | (None, Some(_), _) => {
+                return Err(String::from("cert and key go together"))
+            }
+            (Some(_), Some(_), Some(_)) => {
+                return Err(String::from("either cert and key, or pkcs12"))
+            }
+            _ => {}
+        }
+        let tls_options = settings.ca.is_some()
+            || !settings.pins.is_empty()
+            || settings.insecure
+            || settings.client_certificate.is_some()
+            || settings.client_pkcs12.is_some()
+            || name.is_some();
+        if tls_options && !scheme.uses_tls() {
+            return Err(String::from("TLS options need tls:// or https://"));
+        }
+        // the certificate of an IP address can not be validated without a name
+        settings.name = match name {
+            Some(name) => name,
+            None if host.parse::<IpAddr>().is_err() || !settings.scheme.uses_tls() => {
+                String::from(host)
+            }
+            None if settings.insecure => String::from(host),
+            None => return Err(String::from("an IP address needs ?name=")),
+        };
+        Ok(settings)
+    }
+}
+
+// "host", "host:port", "[ipv6]" or "[ipv6]:port"
+fn split_host_port(authority: &str, default_port: u16) -> Result<(&str, u16), String> {
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(rest) => {
+            let (host, rest) = rest
+                .split_once(']')
+                .ok_or_else(|| format!("invalid address {}", authority))?;
+            match rest.strip_prefix(':') {
+                Some(port) => (host, Some(port)),
+                None if rest.is_empty() => (host, None),
+                None => return Err(format!("invalid address {}", authority)),
+            }
+        }
+        None => match authority.split_once(':') {
+            Some((_, port)) if port.contains(':') => {
+                return Err(format!("IPv6 addresses need brackets: {}", authority))
+            }
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+
+    if host.is_empty() {
+        return Err(format!("missing host in {}", authority));
+    }
+    match port {
+        Some(port) => port
+            .parse()
+            .map(|port| (host, port))
+            .map_err(|_| format!("invalid port {}", port)),
+        None => Ok((host, default_port)),
+    }
+}
+
+pub fn random_id() -> io::Result<u16> {
+    let mut id = [0u8; 2];
+    getrandom::getrandom(&mut id)?;
+    Ok(u16::from_be_bytes(id))
+}
+
+// The question section, as the names of the first question can not be compressed
+// (there is nothing before to point to)
+fn question_section(message: &[u8]) -> Option<&[u8]> {
+    let count = u16::from_be_bytes([*message.get(4)?, *message.get(5)?]);
+    let mut end = 12;
+    for _ in 0..count {
+        loop {
+            let length = *message.get(end)? as usize;
+            end += 1;
+            if length == 0 {
+                break;
+            }
+            // a compression pointer, or an extended label type
+            if length > 63 {
+                return None;
+            }
+            end += length;
+        }
+        // type and class
+        end += 4;
+    }
+    message.get(12..end)
+}
+
+// https://datatracker.ietf.org/doc/html/rfc5452#section-9.1
+// An answer must have the ID, opcode and question of the query, and the QR flag set.
+// Names are compared case-insensitively: some servers do not keep the case (DNS 0x20).
+pub fn is_answer_to(query: &[u8], answer: &[u8]) -> bool {
+    if query.len() < 12 || answer.len() < 12 || query[..2] != answer[..2] {
+        return false;
+    }
+    let is_response = answer[2] & 0x80 != 0;
+    let opcode = |message: &[u8]| (message[2] >> 3) & 0x0f;
+    if !is_response || opcode(query) != opcode(answer) {
+        return false;
+    }
+
+    match (question_section(query), question_section(answer)) {
+        (Some(q), Some(a)) => q.len() == a.len() && names_match(q, a),
+        _ => false,
+    }
+}
+
+// Same questions, except for the case of the names: types and classes must be equal.
+// Labels are compared with their length byte, which is never a letter (below 64).
+fn names_match(query: &[u8], answer: &[u8]) -> bool {
+    let mut position = 0;
+    while position < query.len() {
+        let length = query[position] as usize;
+        if length == 0 {
+            // the type and class that end the question
+            let end = position + 5;
+            if query[position..end] != answer[position..end] {
+                return false;
+            }
+            position = end;
+            continue;
+        }
+        let label = position..position + 1 + length;
+        if !query[label.clone()].eq_ignore_ascii_case(&answer[label]) {
+            return false;
+        }
+        position += 1 + length;
+    }
+    true
+}
+
+// A TLS or plain TCP connection
+trait AsyncStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> AsyncStream for T {}
+
+type Stream = Box<dyn AsyncStream>;
+
+// A TCP connection to the upstream, for DoT, DoH or plain TCP
+pub async fn connect(settings: &UpstreamSettings, timeouts: Timeouts) -> io::Result<TcpStream> {
+    let socket = within(
+        timeouts.connect,
+        TcpStream::connect(&settings.address),
+        || format!("Timeout connecting to upstream {}", settings.address),
+    )
+    .await?;
+    socket.set_nodelay(true)?;
+    Ok(socket)
+}
+
+fn closed() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ConnectionAborted,
+        "Upstream connection closed",
+    )
+}
+
+// A query waiting for its answer
+struct Waiting {
+    query: Bytes,
+    sender: tokio::sync::oneshot::Sender<Bytes>,
+}
+
+#[derive(Default)]
+struct Pending {
+    answers: HashMap<u16, Waiting>,
+    closed: bool,
+}
+
+struct Connection {
+    writer: tokio::sync::Mutex<WriteHalf<Stream>>,
+    pending: Mutex<Pending>,
+}
+
+// Frees the ID of a query that is not waiting anymore. The ID may already be used by
+// another query, once the answer was received: the query itself tells them apart.
+struct Forget<'a> {
+    connection: &'a Connection,
+    id: u16,
+    query: Bytes,
+}
+
+impl Drop for Forget<'_> {
+    fn drop(&mut self) {
+        let mut pending = self.connection.pending.lock().unwrap();
+        if let Some(waiting) = pending.answers.get(&self.id) {
+            if waiting.query.as_ptr() == self.query.as_ptr() {
+                pending.answers.remove(&self.id);
+            }
+        }
+    }
+}
+
+impl Connection {
+    fn in_flight(&self) -> usize {
+        self.pending.lock().unwrap().answers.len()
+    }
+
+    fn is_closed(&self) -> bool {
+        self.pending.lock().unwrap().closed
+    }
+
+    // Pending queries fail right away, instead of waiting for their timeout
+    fn close_pending(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.closed = true;
+        pending.answers.clear();
+    }
+
+    async fn close(&self) {
+        self.close_pending();
+        // close_notify, so the upstream sees a clean close rather than a reset
+        let _ = self.writer.lock().await.shutdown().await;
+    }
+
+    async fn query(&self, query: &[u8], query_timeout: Duration) -> io::Result<Bytes> {
+        let (id, message, receiver) = {
+            let mut pending = self.pending.lock().unwrap();
+            if pending.closed {
+                return Err(closed());
+            }
+            // unpredictable, so answers cannot be forged without seeing the query
+            let mut id = random_id()?;
+            while pending.answers.contains_key(&id) {
+                id = random_id()?;
+            }
+            let mut message = BytesMut::from(query);
+            message[..2].copy_from_slice(&id.to_be_bytes());
+            let message = message.freeze();
+
+            let (sender, receiver) = tokio::sync::oneshot::channel();
+            let waiting = Waiting {
+                query: message.clone(),
+                sender,
+            };
+            pending.answers.insert(id, waiting);
+            (id, message, receiver)
+        };
+
+        // also when the query is cancelled, e.g. it lost a race with another upstream
+        let _forget = Forget {
+            connection: self,
+            id,
+            query: message.clone(),
+        };
+
+        let written = framing::write_message(&mut *self.writer.lock().await, &message).await;
+        if let Err(e) = written {
+            self.close_pending();
+            return Err(io::Error::new(io::ErrorKind::ConnectionAborted, e));
+        }
+
+        match timeout(query_timeout, receiver).await {
+            Ok(Ok(answer)) => Ok(answer),
+            Ok(Err(_)) => Err(closed()),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Timeout waiting for the upstream",
+            )),
+        }
+    }
+
+    // Hands the answers to the waiting queries, until the connection fails or stays
+    // unused for `idle_timeout`
+    async fn read_answers(self: Arc<Self>, mut reader: ReadHalf<Stream>, idle_timeout: Duration) {
+        // see LengthPrefix: reads of it are cancelled by `idle_timeout`
+        let mut prefix = framing::LengthPrefix::default();
+        loop {
+            let size = match timeout(idle_timeout, prefix.read(&mut reader)).await {
+                Ok(Ok(size)) => size,
+                Ok(Err(e)) => {
+                    debug!("Upstream connection closed: {}", e);
+                    break;
+                }
+                Err(_) => {
+                    let mut pending = self.pending.lock().unwrap();
+                    if !pending.answers.is_empty() {
+                        continue;
+                    }
+                    // no query can be added anymore
+                    pending.closed = true;
+                    debug!("Closing idle upstream connection");
+                    break;
+                }
+            };
+
+            let answer = match framing::read_body(&mut reader, size, u16::MAX as usize).await {
+                Ok(answer) => answer,
+                Err(e) => {
+                    warn!("Invalid answer from the upstream: {}", e);
+                    break;
+                }
+            };
+            let id = u16::from_be_bytes([answer[0], answer[1]]);
+            let mut pending = self.pending.lock().unwrap();
+            match pending.answers.get(&id) {
+                Some(waiting) if is_answer_to(&waiting.query, &answer) => {
+                    let waiting = pending.answers.remove(&id).unwrap();
+                    let _ = waiting.sender.send(answer);
+                }
+                // never cached: the query keeps waiting for a valid answer
+                Some(_) => warn!("Discarding mismatched answer from the upstream, ID {}", id),
+                // e.g. the query already timed out
+                None => debug!("Unexpected answer from the upstream, ID {}", id),
+            }
+        }
+        self.close().await;
+    }
+}
+
+pub struct UpstreamPool {
+    settings: UpstreamSettings,
+    // None for plain TCP
+    tls: Option<UpstreamTls>,
+    timeouts: Timeouts,
+    connections: tokio::sync::Mutex<Vec<Arc<Connection>>>,
+}
+
+impl UpstreamPool {
+    pub fn new(settings: UpstreamSettings, timeouts: Timeouts) -> io::Result<Self> {
+        Ok(UpstreamPool {
+            tls: match settings.scheme {
+                Scheme::Tcp => None,
+                _ => Some(UpstreamTls::new(settings.clone(), &[])?),
+            },
+            settings,
+            timeouts,
+            connections: tokio::sync::Mutex::new(vec![]),
+        })
+    }
+
+    // Sends a query (its ID is replaced) and returns the answer
+    pub async fn exchange(&self, query: &[u8]) -> io::Result<Bytes> {
+        let query_timeout = self.timeouts.query;
+        // closed connections are left out by connection()
+        retry_closed(|_| async move {
+            let connection = self.connection().await?;
+            connection.query(query, query_timeout).await
+        })
+        .await
+    }
+
+    // The least busy connection, or a new one when they all have many queries in flight
+    async fn connection(&self) -> io::Result<Arc<Connection>> {
+        // held while connecting, see retry_closed
+        let mut connections = self.connections.lock().await;
+        connections.retain(|c| !c.is_closed());
+
+        if let Some(connection) = connections.iter().min_by_key(|c| c.in_flight()) {
+            if connection.in_flight() < MAX_PIPELINED_QUERIES
+                || connections.len() >= MAX_CONNECTIONS
+            {
+                return Ok(connection.clone());
+            }
+        }
+
+        let connection = self.connect().await?;
+        connections.push(connection.clone());
+        Ok(connection)
+    }
+
+    async fn connect(&self) -> io::Result<Arc<Connection>> {
+        let stream = self.open().await?;
+        info!("Connected to upstream {}", self.settings.address);
+
+        let (reader, writer) = tokio::io::split(stream);
+        let connection = Arc::new(Connection {
+            writer: tokio::sync::Mutex::new(writer),
+            pending: Mutex::new(Pending::default()),
+        });
+        tokio::spawn(connection.clone().read_answers(reader, self.timeouts.idle));
+        Ok(connection)
+    }
+
+    async fn open(&self) -> io::Result<Stream> {
+        let socket = connect(&self.settings, self.timeouts).await?;
+        match &self.tls {
+            Some(tls) => {
+                let handshake = tls.connect(socket);
+                let stream = within(self.timeouts.handshake, handshake, || {
+                    format!(
+                        "Timeout of the TLS handshake with upstream {}",
+                        self.settings.address
+                    )
+                })
+                .await?;
+                Ok(Box::new(stream))
+            }
+            None => Ok(Box::new(socket)),
+        }
+    }
+}
+
+impl Transport for UpstreamPool {
+    fn exchange<'a>(&'a self, query: &'a [u8]) -> BoxFuture<'a, io::Result<Bytes>> {
+        Box::pin(UpstreamPool::exchange(self, query))
+    }
+
+    fn reload(&self) -> io::Result<bool> {
+        match &self.tls {
+            Some(tls) => tls.reload(),
+            None => Ok(false),
+        }
+    }
+
+    fn close(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            for connection in self.connections.lock().await.drain(..) {
+                connection.close().await;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::framing;
+    use crate::server::tests::query as dns_query;
+    use crate::tls::tests::{fixture, settings};
+    use crate::tls::{server_config, CertificateReloader};
+    use crate::upstream::{
+        is_answer_to, Scheme, Timeouts, Transport, UpstreamPool, UpstreamSettings,
+    };
+    use crate::upstream_tls::parse_pin;
+    use crate::upstream_tls::tests::SERVER_PIN;
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    // An upstream that answers with the query itself, waiting for `batch` queries
+    // and answering them in reverse order. With `decoy`, every answer is preceded by
+    // one for another name.
+    pub async fn upstream(batch: usize, decoy: bool) -> (SocketAddr, Arc<AtomicUsize>) {
+        upstream_with(false, batch, decoy).await
+    }
+
+    // With `client_ca`, clients must present a certificate of the test CA
+    async fn upstream_with(
+        client_ca: bool,
+        batch: usize,
+        decoy: bool,
+    ) -> (SocketAddr, Arc<AtomicUsize>) {
+        let settings = settings(client_ca);
+        let certificates = CertificateReloader::new(settings.clone()).unwrap();
+        let acceptor = TlsAcceptor::from(server_config(&settings, certificates, &[]).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let connections = Arc::new(AtomicUsize::new(0));
+
+        let c_connections = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                c_connections.fetch_add(1, Ordering::SeqCst);
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let Ok(mut stream) = acceptor.accept(socket).await else {
+                        return;
+                    };
+                    loop {
+                        let mut queries = vec![];
+                        for _ in 0..batch {
+                            match framing::read_message(&mut stream, 4096).await {
+                                Ok(query) => queries.push(query),
+                                Err(_) => return,
+                            }
+                        }
+                        for query in queries.iter().rev() {
+                            let mut answer = query.to_vec();
+                            answer[2] |= 0x80;
+                            if decoy {
+                                let mut other = answer.clone();
+                                other[13] = b'x';
+                                framing::write_message(&mut stream, &other).await.unwrap();
+                            }
+                            framing::write_message(&mut stream, &answer).await.unwrap();
+                        }
+                    }
+                });
+            }
+        });
+        (address, connections)
+    }
+
+    // trusts the certificate of the fake upstreams, issued for "localhost" by the test CA
+    pub fn settings_for(address: SocketAddr) -> UpstreamSettings {
+        let mut settings = UpstreamSettings::new(address.to_string(), String::from("localhost"));
+        settings.ca = Some(fixture("ca.crt"));
+        settings
+    }
+
+    fn pool(address: SocketAddr, idle_timeout: Duration) -> UpstreamPool {
+        let timeouts = Timeouts {
+            idle: idle_timeout,
+            ..Timeouts::default()
+        };
+        UpstreamPool::new(settings_for(address), timeouts).unwrap()
+    }
+
+    #[test]
+    fn test_upstream_settings() {
+        let parse = |value: &str| value.parse::<UpstreamSettings>();
+        let expected =
+            |address: &str, name: &str| Ok(UpstreamSettings::new(address.into(), name.into()));
+
+        assert_eq!(
+            parse("tls://1.1.1.1:853?name=one.one.one.one"),
+            expected("1.1.1.1:853", "one.one.one.one")
+        );
+        assert_eq!(
+            parse("tls://dns.google"),
+            expected("dns.google:853", "dns.google")
+        );
+        assert_eq!(
+            parse("tls://[2606:4700::1111]?name=one.one.one.one"),
+            expected("[2606:4700::1111]:853", "one.one.one.one")
+        );
+        assert_eq!(
+            parse("tls://[::1]:8853?name=localhost"),
+            expected("[::1]:8853", "localhost")
+        );
+
+        assert_eq!(parse("tls://dns.google?weight=3").unwrap().weight, 3);
+        let settings = parse(&format!(
+            "tls://10.0.0.53?name=dns.internal&ca=/etc/ca.crt&pin={}&pin={}",
+            SERVER_PIN, SERVER_PIN
+        ))
+        .unwrap();
+        assert_eq!(settings.ca, Some(PathBuf::from("/etc/ca.crt")));
+        assert_eq!(settings.pins.len(), 2);
+        assert!(parse("tls://10.0.0.53?insecure=true").unwrap().insecure);
+        let settings = parse("tls://dns.internal?pkcs12=/etc/client.p12&password=secret").unwrap();
+        assert_eq!(
+            settings.client_pkcs12,
+            Some(PathBuf::from("/etc/client.p12"))
+        );
+        assert_eq!(settings.client_pkcs12_password, "secret");
+        assert!(parse("tls://dns.internal?cert=/etc/client.crt&key=/etc/client.key").is_ok());
+        assert!(parse("tls://dns.internal?cert=/etc/client.crt").is_err());
+
+        let settings = parse("https://dns.google/resolve?weight=2").unwrap();
+        assert_eq!(settings.scheme, Scheme::Https);
+        assert_eq!(settings.address, "dns.google:443");
+        assert_eq!(settings.path, "/resolve");
+        assert_eq!(parse("https://dns.google").unwrap().path, "/dns-query");
+        let settings = parse("udp://10.0.0.2").unwrap();
+        assert_eq!(
+            (settings.scheme, settings.address.as_str()),
+            (Scheme::Udp, "10.0.0.2:53")
+        );
+        let settings = parse("tcp://[fd00::2]:5353").unwrap();
+        assert_eq!(
+            (settings.scheme, settings.address.as_str()),
+            (Scheme::Tcp, "[fd00::2]:5353")
+        );
+        assert!(parse("udp://10.0.0.2?ca=/etc/ca.crt").is_err());
+        assert!(parse("tcp://10.0.0.2?name=x").is_err());
+        assert!(parse("tls://dns.google/dns-query").is_err());
+        assert!(parse("quic://dns.google").is_err());
+
+        assert!(parse("1.1.1.1:853").is_err());
+        assert!(parse("tls://dns.google?weight=0").is_err());
+        assert!(parse("tls://1.1.1.1").is_err());
+        assert!(parse("tls://2606:4700::1111?name=x").is_err());
+        assert!(parse("tls://dns.google:99999").is_err());
+        assert!(parse("tls://dns.google?sni=x").is_err());
+        assert!(parse("tls://dns.google?pin=x").is_err());
+        assert!(parse("tls://dns.google?insecure=1").is_err());
+        assert!(parse("tls://?name=x").is_err());
+    }
+
+    fn query(name: u8) -> Vec<u8> {
+        let mut query = vec![0u8; 12];
+        query.push(name);
+        query
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_queries() {
+        let (address, connections) = upstream(2, false).await;
+        let pool = pool(address, Duration::from_secs(30));
+
+        // answered out of order, on the same connection
+        let queries: Vec<Vec<u8>> = (1..=4).map(query).collect();
+        let (a, b) = tokio::join!(pool.exchange(&queries[0]), pool.exchange(&queries[1]));
+        assert_eq!(a.unwrap()[12], 1);
+        assert_eq!(b.unwrap()[12], 2);
+        let (a, b) = tokio::join!(pool.exchange(&queries[2]), pool.exchange(&queries[3]));
+        assert_eq!((a.unwrap()[12], b.unwrap()[12]), (3, 4));
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() {
+        let (address, connections) = upstream(1, false).await;
+        let pool = pool(address, Duration::from_millis(200));
+
+        assert_eq!(pool.exchange(&query(1)).await.unwrap()[12], 1);
+        // the idle connection is closed, the next query opens a new one
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(pool.exchange(&query(2)).await.unwrap()[12], 2);
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+
+        // and after a close, e.g. on shutdown
+        pool.close().await;
+        assert_eq!(pool.exchange(&query(3)).await.unwrap()[12], 3);
+        assert_eq!(connections.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_is_answer_to() {
+        let query = dns_query(0x1234, "www.example.com").to_vec();
+        let answer = |change: &dyn Fn(&mut Vec<u8>)| {
+            let mut answer = query.clone();
+            answer[2] |= 0x80;
+            change(&mut answer);
+            answer
+        };
+
+        assert!(is_answer_to(&query, &answer(&|_| {})));
+        // DNS 0x20: the case of the name may differ
+        assert!(is_answer_to(&query, &answer(&|a| a[13] = b'W')));
+        // with records after the question
+        assert!(is_answer_to(
+            &query,
+            &answer(&|a| a.extend_from_slice(&[0xc0, 12]))
+        ));
+
+        assert!(!is_answer_to(&query, &query));
+        assert!(!is_answer_to(&query, &answer(&|a| a[1] = 0x35)));
+        // opcode NOTIFY
+        assert!(!is_answer_to(&query, &answer(&|a| a[2] |= 4 << 3)));
+        assert!(!is_answer_to(&query, &answer(&|a| a[14] = b'x')));
+        // type AAAA, class CH
+        let end = query.len();
+        assert!(!is_answer_to(&query, &answer(&|a| a[end - 3] = 28)));
+        assert!(!is_answer_to(&query, &answer(&|a| a[end - 1] = 3)));
+        // no question, or a compressed one
+        assert!(!is_answer_to(&query, &answer(&|a| a.truncate(12))));
+        assert!(!is_answer_to(
+            &query,
+            &answer(&|a| {
+                a.truncate(12);
+                a.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1]);
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_query_forgotten() {
+        let (address, _) = upstream(2, false).await;
+        let pool = pool(address, Duration::from_secs(30));
+
+        // never answered: the upstream waits for a second query
+        let cancelled =
+            tokio::time::timeout(Duration::from_millis(200), pool.exchange(&query(1))).await;
+        assert!(cancelled.is_err());
+        let connections = pool.connections.lock().await;
+        assert_eq!(connections[0].in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_certificate_validation() {
+        let (address, _) = upstream(1, false).await;
+        let exchange = |settings: UpstreamSettings| async move {
+            let pool = UpstreamPool::new(settings, Timeouts::default()).unwrap();
+            pool.exchange(&query(1)).await
+        };
+
+        let mut pinned = settings_for(address);
+        pinned.pins = vec![parse_pin(SERVER_PIN).unwrap()];
+        assert!(exchange(pinned.clone()).await.is_ok());
+        pinned.pins = vec![vec![0; 32]];
+        assert!(exchange(pinned).await.is_err());
+
+        // the test CA is not trusted by the system
+        let mut untrusted = settings_for(address);
+        untrusted.ca = None;
+        assert!(exchange(untrusted.clone()).await.is_err());
+        untrusted.insecure = true;
+        assert!(exchange(untrusted).await.is_ok());
+
+        // nor the system CAs by a CA bundle
+        let mut other_ca = settings_for(address);
+        other_ca.ca = Some(fixture("client.crt"));
+        assert!(exchange(other_ca).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_client_certificate() {
+        let (address, _) = upstream_with(true, 1, false).await;
+        let exchange = |settings: UpstreamSettings| async move {
+            let pool = UpstreamPool::new(settings, Timeouts::default()).unwrap();
+            pool.exchange(&query(1)).await
+        };
+
+        assert!(exchange(settings_for(address)).await.is_err());
+
+        let mut pem = settings_for(address);
+        pem.client_certificate = Some(fixture("client.crt"));
+        pem.client_key = Some(fixture("client.key"));
+        assert!(exchange(pem).await.is_ok());
+
+        let mut pkcs12 = settings_for(address);
+        pkcs12.client_pkcs12 = Some(fixture("client.p12"));
+        pkcs12.client_pkcs12_password = String::from("12345");
+        assert!(exchange(pkcs12.clone()).await.is_ok());
+
+        pkcs12.client_pkcs12_password = String::from("wrong");
+        assert!(UpstreamPool::new(pkcs12, Timeouts::default()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handshake_timeout() {
+        // connections are accepted, but the TLS handshake is never answered
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut sockets = vec![];
+            loop {
+                sockets.push(listener.accept().await.unwrap().0);
+            }
+        });
+
+        let timeouts = Timeouts {
+            handshake: Duration::from_millis(200),
+            ..Timeouts::default()
+        };
+        let pool = UpstreamPool::new(settings_for(address), timeouts).unwrap();
+        let error = pool.exchange(&query(1)).await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn test_mismatched_answer_discarded() {
+        let (address, _) = upstream(1, true).await;
+        let pool = pool(address, Duration::from_secs(30));
+
+        // the answer for another name, with the same ID, is skipped
+        let query = dns_query(0, "www.example.com");
+        let answer = pool.exchange(&query).await.unwrap();
+        assert_eq!(answer[12..], query[12..]);
+    }
+}
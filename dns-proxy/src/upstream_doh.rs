@@ -0,0 +1,218 @@
+use crate::doh::DNS_MESSAGE;
+use crate::upstream::{
+    self, is_answer_to, retry_closed, within, BoxFuture, Timeouts, Transport, UpstreamSettings,
+};
+use crate::upstream_tls::UpstreamTls;
+use bytes::{Bytes, BytesMut};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::client::conn::http2::{self, SendRequest};
+use hyper::header::{ACCEPT, CONTENT_TYPE};
+use hyper::{Request, StatusCode, Uri};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use log::{debug, info};
+use std::io;
+use tokio::net::TcpStream;
+
+// https://datatracker.ietf.org/doc/html/rfc8484#section-4.1
+// Queries are POSTed on a single HTTP/2 connection, reused as long as it is open:
+// HTTP/2 multiplexes the requests, so there is no need for a pool
+
+type Sender = SendRequest<Full<Bytes>>;
+
+pub struct DohUpstream {
+    settings: UpstreamSettings,
+    tls: UpstreamTls,
+    timeouts: Timeouts,
+    uri: Uri,
+    // the connection in use, replaced once closed (see upstream::retry_closed)
+    sender: tokio::sync::Mutex<Option<Sender>>,
+}
+
+fn aborted<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::ConnectionAborted, e)
+}
+
+impl DohUpstream {
+    pub fn new(settings: UpstreamSettings, timeouts: Timeouts) -> io::Result<Self> {
+        let uri = format!("https://{}{}", settings.address, settings.path)
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(DohUpstream {
+            tls: UpstreamTls::new(settings.clone(), &["h2"])?,
+            settings,
+            timeouts,
+            uri,
+            sender: tokio::sync::Mutex::new(None),
+        })
+    }
+
+    pub async fn exchange(&self, query: &[u8]) -> io::Result<Bytes> {
+        // https://datatracker.ietf.org/doc/html/rfc8484#section-4.1
+        // the ID is 0, so the same queries get the same (HTTP cacheable) requests
+        let mut message = BytesMut::from(query);
+        message[..2].fill(0);
+        let message = message.freeze();
+
+        let message = &message;
+        retry_closed(|retry| async move {
+            // the connection the query failed on
+            if retry {
+                self.sender.lock().await.take();
+            }
+            let sender = self.sender().await?;
+            self.post(sender, message).await
+        })
+        .await
+    }
+
+    async fn sender(&self) -> io::Result<Sender> {
+        let mut sender = self.sender.lock().await;
+        match &*sender {
+            Some(current) if !current.is_closed() => Ok(current.clone()),
+            _ => {
+                let new = self.connect().await?;
+                *sender = Some(new.clone());
+                Ok(new)
+            }
+        }
+    }
+
+    async fn connect(&self) -> io::Result<Sender> {
+        let socket = upstream::connect(&self.settings, self.timeouts).await?;
+        let (sender, connection) = within(self.timeouts.handshake, self.handshake(socket), || {
+            format!(
+                "Timeout of the handshakes with upstream {}",
+                self.settings.address
+            )
+        })
+        .await?;
+        info!("Connected to upstream {}", self.settings.address);
+
+        let address = self.settings.address.clone();
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                debug!("Upstream connection to {} closed: {}", address, e);
+            }
+        });
+        Ok(sender)
+    }
+
+    // TLS, then HTTP/2
+    async fn handshake(
+        &self,
+        socket: TcpStream,
+    ) -> io::Result<(
+        Sender,
+        http2::Connection<TokioIo<crate::upstream_tls::Stream>, Full<Bytes>, TokioExecutor>,
+    )> {
+        let stream = self.tls.connect(socket).await?;
+        http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::ConnectionRefused, e))
+    }
+
+    async fn post(&self, mut sender: Sender, query: &Bytes) -> io::Result<Bytes> {
+        let request = Request::post(self.uri.clone())
+            .header(CONTENT_TYPE, DNS_MESSAGE)
+            .header(ACCEPT, DNS_MESSAGE)
+            .body(Full::new(query.clone()))
+            .map_err(io::Error::other)?;
+
+        let answer = async {
+            sender.ready().await.map_err(aborted)?;
+            let response = sender.send_request(request).await.map_err(aborted)?;
+            if response.status() != StatusCode::OK {
+                return Err(io::Error::other(format!(
+                    "Upstream {} answered with HTTP status {}",
+                    self.settings.address,
+                    response.status()
+                )));
+            }
+            // https://datatracker.ietf.org/doc/html/rfc8484#section-6
+            let body = Limited::new(response.into_body(), u16::MAX as usize)
+                .collect()
+                .await
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            Ok(body.to_bytes())
+        };
+        let answer = within(self.timeouts.query, answer, || {
+            String::from("Timeout waiting for the upstream")
+        })
+        .await?;
+
+        // never cached
+        if !is_answer_to(query, &answer) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Mismatched answer from upstream {}", self.settings.address),
+            ));
+        }
+        Ok(answer)
+    }
+}
+
+impl Transport for DohUpstream {
+    fn exchange<'a>(&'a self, query: &'a [u8]) -> BoxFuture<'a, io::Result<Bytes>> {
+        Box::pin(DohUpstream::exchange(self, query))
+    }
+
+    fn reload(&self) -> io::Result<bool> {
+        self.tls.reload()
+    }
+
+    // the connection ends once its last sender is dropped
+    fn close(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            self.sender.lock().await.take();
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::doh::tests::serve;
+    use crate::server::tests::query;
+    use crate::upstream::tests::settings_for;
+    use crate::upstream::{Scheme, Timeouts, UpstreamSettings};
+    use crate::upstream_doh::DohUpstream;
+
+    fn doh_settings(address: std::net::SocketAddr, path: &str) -> UpstreamSettings {
+        let mut settings = settings_for(address);
+        settings.scheme = Scheme::Https;
+        settings.path = String::from(path);
+        settings
+    }
+
+    #[tokio::test]
+    async fn test_doh_upstream() {
+        let address = serve(true).await;
+        let upstream =
+            DohUpstream::new(doh_settings(address, "/dns-query"), Timeouts::default()).unwrap();
+
+        let a = upstream.exchange(&query(1, "a.example.com")).await.unwrap();
+        let b = upstream.exchange(&query(2, "a.example.com")).await.unwrap();
+        assert_eq!(a[..2], [0, 0]);
+        assert_eq!(a[6..8], [0, 1]);
+        assert_eq!(b[6..8], [0, 1]);
+
+        // the connection is kept for the next queries
+        assert!(!upstream.sender.lock().await.as_ref().unwrap().is_closed());
+    }
+
+    #[tokio::test]
+    async fn test_doh_upstream_errors() {
+        let address = serve(true).await;
+        let upstream =
+            DohUpstream::new(doh_settings(address, "/other"), Timeouts::default()).unwrap();
+        assert!(upstream.exchange(&query(1, "a.example.com")).await.is_err());
+
+        // the server is not trusted
+        let mut settings = doh_settings(address, "/dns-query");
+        settings.ca = None;
+        let upstream = DohUpstream::new(settings, Timeouts::default()).unwrap();
+        assert!(upstream.exchange(&query(1, "a.example.com")).await.is_err());
+    }
+}
@@ -0,0 +1,522 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use log::{error, warn};
+use ring::digest::{digest, SHA256};
+use std::io;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::CertificateDer;
+
+use crate::upstream::UpstreamSettings;
+
+// TLS towards an upstream: its own CA bundle (e.g. a private CA), a client certificate,
+// SPKI pins, or no validation at all for testing. Done by native-tls (OpenSSL on Linux)
+// or, with the "rustls" feature, by rustls. Features add up, so with both (e.g.
+// --all-features) rustls is used.
+
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+compile_error!("Either the \"native-tls\" or the \"rustls\" feature is needed");
+
+pub use backend::Stream;
+
+// The files of an upstream, compared to notice when any of them changes
+#[derive(PartialEq, Eq)]
+struct Files {
+    ca: Option<Vec<u8>>,
+    certificate: Option<Vec<u8>>,
+    key: Option<Vec<u8>>,
+    pkcs12: Option<Vec<u8>>,
+}
+
+impl Files {
+    fn read(settings: &UpstreamSettings) -> io::Result<Self> {
+        let read = |path: &Option<PathBuf>| match path {
+            Some(path) => std::fs::read(path)
+                .map(Some)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e))),
+            None => Ok(None),
+        };
+        Ok(Files {
+            ca: read(&settings.ca)?,
+            certificate: read(&settings.client_certificate)?,
+            key: read(&settings.client_key)?,
+            pkcs12: read(&settings.client_pkcs12)?,
+        })
+    }
+}
+
+// The connector of an upstream, rebuilt when its files change (e.g. a client
+// certificate renewed on disk): new connections use the new one
+pub struct UpstreamTls {
+    settings: UpstreamSettings,
+    // https://datatracker.ietf.org/doc/html/rfc7301, e.g. "h2" for DoH
+    alpn: Vec<String>,
+    current: RwLock<(Files, backend::Connector)>,
+}
+
+impl UpstreamTls {
+    pub fn new(settings: UpstreamSettings, alpn: &[&str]) -> io::Result<Self> {
+        let alpn: Vec<String> = alpn.iter().map(|p| p.to_string()).collect();
+        let files = Files::read(&settings)?;
+        let connector = connector(&settings, &files, &alpn)?;
+        Ok(UpstreamTls {
+            settings,
+            alpn,
+            current: RwLock::new((files, connector)),
+        })
+    }
+
+    // Handshake on a new connection, then the pins are checked
+    pub async fn connect(&self, socket: TcpStream) -> io::Result<Stream> {
+        let connector = self.current.read().unwrap().1.clone();
+        let stream = backend::handshake(&connector, &self.settings.name, socket)
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::ConnectionRefused, e))?;
+        check_pins(&self.settings, backend::peer_certificate(&stream)?)?;
+        Ok(stream)
+    }
+
+    // Returns true when the files changed. On errors (e.g. a key not written yet), the
+    // current connector is kept.
+    pub fn reload(&self) -> io::Result<bool> {
+        let files = Files::read(&self.settings)?;
+        if self.current.read().unwrap().0 == files {
+            return Ok(false);
+        }
+        let connector = connector(&self.settings, &files, &self.alpn)?;
+        *self.current.write().unwrap() = (files, connector);
+        Ok(true)
+    }
+}
+
+fn invalid<E: std::fmt::Display>(path: &Option<PathBuf>, e: E) -> io::Error {
+    let path = path.as_deref().map(|p| p.display().to_string());
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.unwrap_or_default(), e),
+    )
+}
+
+fn connector(
+    settings: &UpstreamSettings,
+    files: &Files,
+    alpn: &[String],
+) -> io::Result<backend::Connector> {
+    if settings.insecure {
+        warn!(
+            "Certificates of upstream {} are not validated (insecure=true), only use it for testing",
+            settings.address
+        );
+    }
+    backend::connector(settings, files, alpn)
+}
+
+// Both backends only take client keys in PKCS#8 ("BEGIN PRIVATE KEY")
+fn pkcs8_expected<E: std::fmt::Display>(e: E) -> String {
+    format!(
+        "{} (a PKCS#8 key is expected, e.g. converted with openssl pkcs8 -topk8 -nocrypt)",
+        e
+    )
+}
+
+fn ca_certificates(
+    settings: &UpstreamSettings,
+    ca: &[u8],
+) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certificates = CertificateDer::pem_slice_iter(ca)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| invalid(&settings.ca, e))?;
+    if certificates.is_empty() {
+        return Err(invalid(&settings.ca, "no certificate found"));
+    }
+    Ok(certificates)
+}
+
+#[cfg(not(feature = "rustls"))]
+mod backend {
+    use std::io;
+    use tokio::net::TcpStream;
+    use tokio_native_tls::native_tls::{self, Certificate, Identity};
+
+    use crate::upstream::UpstreamSettings;
+    use crate::upstream_tls::{ca_certificates, invalid, pkcs8_expected, Files};
+
+    pub type Stream = tokio_native_tls::TlsStream<TcpStream>;
+    pub type Connector = tokio_native_tls::TlsConnector;
+
+    // With a CA bundle, only its certificates are trusted
+    pub fn connector(
+        settings: &UpstreamSettings,
+        files: &Files,
+        alpn: &[String],
+    ) -> io::Result<Connector> {
+        let mut builder = native_tls::TlsConnector::builder();
+        if !alpn.is_empty() {
+            let alpn: Vec<&str> = alpn.iter().map(String::as_str).collect();
+            builder.request_alpns(&alpn);
+        }
+        if let Some(ca) = &files.ca {
+            builder.disable_built_in_roots(true);
+            for certificate in ca_certificates(settings, ca)? {
+                let certificate =
+                    Certificate::from_der(&certificate).map_err(|e| invalid(&settings.ca, e))?;
+                builder.add_root_certificate(certificate);
+            }
+        }
+
+        match (&files.certificate, &files.key, &files.pkcs12) {
+            (Some(certificate), Some(key), _) => {
+                let identity = Identity::from_pkcs8(certificate, key)
+                    .map_err(|e| invalid(&settings.client_key, pkcs8_expected(e)))?;
+                builder.identity(identity);
+            }
+            (_, _, Some(pkcs12)) => {
+                let identity = Identity::from_pkcs12(pkcs12, &settings.client_pkcs12_password)
+                    .map_err(|e| invalid(&settings.client_pkcs12, e))?;
+                builder.identity(identity);
+            }
+            _ => {}
+        }
+
+        if settings.insecure {
+            builder.danger_accept_invalid_certs(true);
+            builder.danger_accept_invalid_hostnames(true);
+        }
+        Ok(builder.build().map_err(io::Error::other)?.into())
+    }
+
+    pub async fn handshake(
+        connector: &Connector,
+        name: &str,
+        socket: TcpStream,
+    ) -> io::Result<Stream> {
+        connector
+            .connect(name, socket)
+            .await
+            .map_err(io::Error::other)
+    }
+
+    pub fn peer_certificate(stream: &Stream) -> io::Result<Option<Vec<u8>>> {
+        match stream.get_ref().peer_certificate() {
+            Ok(Some(certificate)) => Ok(Some(certificate.to_der().map_err(io::Error::other)?)),
+            Ok(None) => Ok(None),
+            Err(e) => Err(io::Error::other(e)),
+        }
+    }
+}
+
+#[cfg(feature = "rustls")]
+mod backend {
+    use log::warn;
+    use std::io;
+    use std::sync::Arc;
+    use tokio::net::TcpStream;
+    use tokio_rustls::rustls::client::danger::{
+        HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+    };
+    use tokio_rustls::rustls::crypto::{
+        verify_tls12_signature, verify_tls13_signature, CryptoProvider,
+    };
+    use tokio_rustls::rustls::pki_types::pem::PemObject;
+    use tokio_rustls::rustls::pki_types::{
+        CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime,
+    };
+    use tokio_rustls::rustls::{
+        ClientConfig, DigitallySignedStruct, Error, RootCertStore, SignatureScheme,
+    };
+
+    use crate::tls::provider;
+    use crate::upstream::UpstreamSettings;
+    use crate::upstream_tls::{ca_certificates, invalid, pkcs8_expected, Files};
+
+    pub type Stream = tokio_rustls::client::TlsStream<TcpStream>;
+    pub type Connector = tokio_rustls::TlsConnector;
+
+    // With a CA bundle, only its certificates are trusted
+    pub fn connector(
+        settings: &UpstreamSettings,
+        files: &Files,
+        alpn: &[String],
+    ) -> io::Result<Connector> {
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?;
+        let builder = if settings.insecure {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(NoVerification(provider())))
+        } else {
+            builder.with_root_certificates(roots(settings, files)?)
+        };
+
+        let mut config = match (&files.certificate, &files.key, &files.pkcs12) {
+            (Some(certificate), Some(key), _) => {
+                let chain = CertificateDer::pem_slice_iter(certificate)
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| invalid(&settings.client_certificate, e))?;
+                // PKCS#8 only, as native-tls
+                let key = PrivatePkcs8KeyDer::from_pem_slice(key)
+                    .map_err(|e| invalid(&settings.client_key, pkcs8_expected(e)))?;
+                builder
+                    .with_client_auth_cert(chain, key.into())
+                    .map_err(|e| invalid(&settings.client_key, e))?
+            }
+            (_, _, Some(pkcs12)) => {
+                let (chain, key) = read_pkcs12(pkcs12, &settings.client_pkcs12_password)
+                    .map_err(|e| invalid(&settings.client_pkcs12, e))?;
+                builder
+                    .with_client_auth_cert(chain, key)
+                    .map_err(|e| invalid(&settings.client_pkcs12, e))?
+            }
+            _ => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+        Ok(Connector::from(Arc::new(config)))
+    }
+
+    // The certificate chain and key of a PKCS#12 archive
+    fn read_pkcs12(
+        data: &[u8],
+        password: &str,
+    ) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), String> {
+        let keystore =
+            p12_keystore::KeyStore::from_pkcs12(data, password).map_err(|e| e.to_string())?;
+        let (_, chain) = keystore
+            .private_key_chain()
+            .ok_or_else(|| String::from("no private key found"))?;
+        let certificates = chain
+            .chain()
+            .iter()
+            .map(|c| CertificateDer::from(c.as_der().to_vec()))
+            .collect();
+        let key = PrivatePkcs8KeyDer::from(chain.key().to_vec());
+        Ok((certificates, key.into()))
+    }
+
+    fn roots(settings: &UpstreamSettings, files: &Files) -> io::Result<RootCertStore> {
+        let mut roots = RootCertStore::empty();
+        match &files.ca {
+            Some(ca) => {
+                for certificate in ca_certificates(settings, ca)? {
+                    roots
+                        .add(certificate)
+                        .map_err(|e| invalid(&settings.ca, e))?;
+                }
+            }
+            // the same as OpenSSL, SSL_CERT_FILE and SSL_CERT_DIR included
+            None => {
+                let system = rustls_native_certs::load_native_certs();
+                for e in system.errors {
+                    warn!("Error loading the system CAs: {}", e);
+                }
+                roots.add_parsable_certificates(system.certs);
+            }
+        }
+        Ok(roots)
+    }
+
+    pub async fn handshake(
+        connector: &Connector,
+        name: &str,
+        socket: TcpStream,
+    ) -> io::Result<Stream> {
+        let name = ServerName::try_from(name.to_string())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        connector.connect(name, socket).await
+    }
+
+    pub fn peer_certificate(stream: &Stream) -> io::Result<Option<Vec<u8>>> {
+        let certificates = stream.get_ref().1.peer_certificates();
+        Ok(certificates.and_then(|c| c.first()).map(|c| c.to_vec()))
+    }
+
+    // Insecure mode: any certificate is accepted, but the handshake signatures are
+    // still verified
+    #[derive(Debug)]
+    struct NoVerification(Arc<CryptoProvider>);
+
+    impl ServerCertVerifier for NoVerification {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &CertificateDer<'_>,
+            _intermediates: &[CertificateDer<'_>],
+            _server_name: &ServerName<'_>,
+            _ocsp_response: &[u8],
+            _now: UnixTime,
+        ) -> Result<ServerCertVerified, Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            message: &[u8],
+            certificate: &CertificateDer<'_>,
+            signature: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, Error> {
+            let algorithms = &self.0.signature_verification_algorithms;
+            verify_tls12_signature(message, certificate, signature, algorithms)
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            message: &[u8],
+            certificate: &CertificateDer<'_>,
+            signature: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, Error> {
+            let algorithms = &self.0.signature_verification_algorithms;
+            verify_tls13_signature(message, certificate, signature, algorithms)
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            self.0.signature_verification_algorithms.supported_schemes()
+        }
+    }
+}
+
+// https://datatracker.ietf.org/doc/html/rfc7858#section-4.2
+// With pins, the public key of the upstream certificate must be one of them: the
+// base64 SHA-256 digest of its SubjectPublicKeyInfo, as in RFC 7469
+fn check_pins(settings: &UpstreamSettings, certificate: Option<Vec<u8>>) -> io::Result<()> {
+    if settings.pins.is_empty() {
+        return Ok(());
+    }
+
+    let certificate = certificate
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "No upstream certificate"))?;
+    let pin = spki_pin(&certificate).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "Invalid upstream certificate")
+    })?;
+
+    if !settings.pins.contains(&pin) {
+        error!(
+            "Certificate of upstream {} does not match its pins: got pin-sha256=\"{}\", expected {}",
+            settings.address,
+            STANDARD.encode(pin),
+            settings
+                .pins
+                .iter()
+                .map(|pin| format!("\"{}\"", STANDARD.encode(pin)))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Upstream certificate does not match the SPKI pins",
+        ));
+    }
+    Ok(())
+}
+
+// A pin as written in the configuration, e.g. "IPKeZwyZPeyLuqfeK0MLldtQx5W/e3EhxH2XtQsIiAQ="
+pub fn parse_pin(value: &str) -> Result<Vec<u8>, String> {
+    match STANDARD.decode(value) {
+        Ok(pin) if pin.len() == SHA256.output_len() => Ok(pin),
+        _ => Err(format!("invalid SHA-256 pin {}", value)),
+    }
+}
+
+fn spki_pin(certificate: &[u8]) -> Option<Vec<u8>> {
+    let spki = subject_public_key_info(certificate)?;
+    Some(digest(&SHA256, spki).as_ref().to_vec())
+}
+
+// The DER element at the start of `data`: its tag, its contents and what follows
+fn der_element(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *data.first()?;
+    let first = *data.get(1)? as usize;
+    let (length, header) = if first < 0x80 {
+        (first, 2)
+    } else {
+        // long form: the number of length bytes
+        let count = first & 0x7f;
+        if count == 0 || count > 4 {
+            return None;
+        }
+        let mut length = 0usize;
+        for i in 0..count {
+            length = (length << 8) | *data.get(2 + i)? as usize;
+        }
+        (length, 2 + count)
+    };
+    let end = header.checked_add(length)?;
+    Some((tag, data.get(header..end)?, data.get(end..)?))
+}
+
+// https://datatracker.ietf.org/doc/html/rfc5280#section-4.1
+// Certificate ::= SEQUENCE { tbsCertificate, signatureAlgorithm, signature }
+// TBSCertificate ::= SEQUENCE { [0] version OPTIONAL, serialNumber, signature, issuer,
+//     validity, subject, subjectPublicKeyInfo, ... }
+fn subject_public_key_info(certificate: &[u8]) -> Option<&[u8]> {
+    const SEQUENCE: u8 = 0x30;
+    const VERSION: u8 = 0xa0;
+
+    let (_, certificate, _) = der_element(certificate)?;
+    let (_, mut tbs, _) = der_element(certificate)?;
+    if tbs.first() == Some(&VERSION) {
+        tbs = der_element(tbs)?.2;
+    }
+    // serial number, signature, issuer, validity and subject
+    for _ in 0..5 {
+        tbs = der_element(tbs)?.2;
+    }
+    match der_element(tbs)? {
+        (SEQUENCE, _, rest) => Some(&tbs[..tbs.len() - rest.len()]),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::tls::load_certificates;
+    use crate::tls::tests::fixture;
+    use crate::upstream::UpstreamSettings;
+    use crate::upstream_tls::{parse_pin, spki_pin, UpstreamTls};
+
+    // openssl x509 -in server.crt -pubkey -noout | openssl pkey -pubin -outform der \
+    //     | openssl dgst -sha256 -binary | base64
+    pub const SERVER_PIN: &str = "IPKeZwyZPeyLuqfeK0MLldtQx5W/e3EhxH2XtQsIiAQ=";
+
+    #[test]
+    fn test_spki_pin() {
+        let certificate = &load_certificates(&fixture("server.crt")).unwrap()[0];
+        assert_eq!(spki_pin(certificate), Some(parse_pin(SERVER_PIN).unwrap()));
+
+        assert_eq!(spki_pin(&certificate[..100]), None);
+        assert_eq!(spki_pin(b""), None);
+    }
+
+    #[test]
+    fn test_parse_pin() {
+        assert!(parse_pin(SERVER_PIN).is_ok());
+        assert!(parse_pin("IPKeZwyZPeyLuqfeK0MLldtQx5W").is_err());
+        assert!(parse_pin("not base64!").is_err());
+    }
+
+    #[test]
+    fn test_reload() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut settings = UpstreamSettings::new(String::from("127.0.0.1:853"), String::new());
+        settings.client_certificate = Some(directory.path().join("client.crt"));
+        settings.client_key = Some(directory.path().join("client.key"));
+        std::fs::copy(fixture("client.crt"), directory.path().join("client.crt")).unwrap();
+        std::fs::copy(fixture("client.key"), directory.path().join("client.key")).unwrap();
+
+        let tls = UpstreamTls::new(settings, &[]).unwrap();
+        assert!(!tls.reload().unwrap());
+
+        // a key that does not match is refused, the current connector is kept
+        std::fs::copy(fixture("server.crt"), directory.path().join("client.crt")).unwrap();
+        assert!(tls.reload().is_err());
+        std::fs::copy(fixture("client.crt"), directory.path().join("client.crt")).unwrap();
+        assert!(!tls.reload().unwrap());
+
+        // rotated
+        let mut renewed = std::fs::read_to_string(fixture("client.crt")).unwrap();
+        renewed.push('\n');
+        std::fs::write(directory.path().join("client.crt"), renewed).unwrap();
+        assert!(tls.reload().unwrap());
+        assert!(!tls.reload().unwrap());
+    }
+}
@@ -0,0 +1,192 @@
+use crate::upstream::{
+    is_answer_to, random_id, within, BoxFuture, Scheme, Timeouts, Transport, UpstreamPool,
+    UpstreamSettings,
+};
+use bytes::{Bytes, BytesMut};
+use log::{debug, warn};
+use std::io;
+use tokio::net::{lookup_host, UdpSocket};
+
+// https://datatracker.ietf.org/doc/html/rfc1035#section-4.2.1
+// Plain DNS over UDP, e.g. towards a resolver of the local network. A truncated answer
+// is asked again over TCP.
+
+pub struct UdpUpstream {
+    settings: UpstreamSettings,
+    timeouts: Timeouts,
+    tcp: UpstreamPool,
+}
+
+impl UdpUpstream {
+    pub fn new(settings: UpstreamSettings, timeouts: Timeouts) -> io::Result<Self> {
+        let tcp = UpstreamSettings {
+            scheme: Scheme::Tcp,
+            ..settings.clone()
+        };
+        Ok(UdpUpstream {
+            tcp: UpstreamPool::new(tcp, timeouts)?,
+            settings,
+            timeouts,
+        })
+    }
+
+    pub async fn exchange(&self, query: &[u8]) -> io::Result<Bytes> {
+        let answer = within(self.timeouts.query, self.query(query), || {
+            String::from("Timeout waiting for the upstream")
+        })
+        .await?;
+
+        // the TC flag
+        if answer[2] & 0x02 != 0 {
+            debug!(
+                "Truncated answer from upstream {}, retrying over TCP",
+                self.settings.address
+            );
+            return self.tcp.exchange(query).await;
+        }
+        Ok(answer)
+    }
+
+    // https://datatracker.ietf.org/doc/html/rfc5452#section-9.2
+    // A new socket for each query, so the source port is random as well as the ID
+    async fn query(&self, query: &[u8]) -> io::Result<Bytes> {
+        let address = lookup_host(&self.settings.address)
+            .await?
+            .next()
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("No address for upstream {}", self.settings.address),
+                )
+            })?;
+        let local = if address.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(address).await?;
+
+        let mut message = BytesMut::from(query);
+        message[..2].copy_from_slice(&random_id()?.to_be_bytes());
+        socket.send(&message).await?;
+
+        let mut buffer = vec![0u8; u16::MAX as usize];
+        loop {
+            let size = socket.recv(&mut buffer).await?;
+            let answer = &buffer[..size];
+            if is_answer_to(&message, answer) {
+                return Ok(Bytes::copy_from_slice(answer));
+            }
+            // a forged or stray datagram must not end the wait: the real answer
+            // may still come, until the query timeout
+            warn!(
+                "Discarding mismatched answer from upstream {}",
+                self.settings.address
+            );
+        }
+    }
+}
+
+impl Transport for UdpUpstream {
+    fn exchange<'a>(&'a self, query: &'a [u8]) -> BoxFuture<'a, io::Result<Bytes>> {
+        Box::pin(UdpUpstream::exchange(self, query))
+    }
+
+    fn close(&self) -> BoxFuture<'_, ()> {
+        Transport::close(&self.tcp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::framing;
+    use crate::upstream::{transport, Timeouts, UpstreamSettings};
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::net::{TcpListener, UdpSocket};
+
+    fn query(name: u8) -> Vec<u8> {
+        let mut query = vec![0u8; 12];
+        query.push(name);
+        query
+    }
+
+    // A plain upstream that answers with the query itself, on UDP and TCP on the same
+    // port. Over UDP, the answer is preceded by a decoy with another ID, and it is
+    // truncated when `truncate` is set.
+    async fn plain_upstream(truncate: bool) -> (SocketAddr, Arc<AtomicUsize>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        let listener = TcpListener::bind(address).await.unwrap();
+        let tcp_queries = Arc::new(AtomicUsize::new(0));
+
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 512];
+            loop {
+                let (size, client) = socket.recv_from(&mut buffer).await.unwrap();
+                let mut answer = buffer[..size].to_vec();
+                answer[2] |= 0x80;
+                if truncate {
+                    answer[2] |= 0x02;
+                }
+                let mut decoy = answer.clone();
+                decoy[0] ^= 0xff;
+                socket.send_to(&decoy, client).await.unwrap();
+                socket.send_to(&answer, client).await.unwrap();
+            }
+        });
+
+        let c_tcp_queries = tcp_queries.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let tcp_queries = c_tcp_queries.clone();
+                tokio::spawn(async move {
+                    while let Ok(query) = framing::read_message(&mut socket, 4096).await {
+                        tcp_queries.fetch_add(1, Ordering::SeqCst);
+                        let mut answer = query.to_vec();
+                        answer[2] |= 0x80;
+                        framing::write_message(&mut socket, &answer).await.unwrap();
+                    }
+                });
+            }
+        });
+        (address, tcp_queries)
+    }
+
+    fn settings(scheme: &str, address: SocketAddr) -> UpstreamSettings {
+        format!("{}://{}", scheme, address).parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_udp_upstream() {
+        let (address, tcp_queries) = plain_upstream(false).await;
+        let upstream = transport(settings("udp", address), Timeouts::default()).unwrap();
+
+        let answer = upstream.exchange(&query(1)).await.unwrap();
+        assert_eq!((answer[2] & 0x82, answer[12]), (0x80, 1));
+        assert_eq!(tcp_queries.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_answer() {
+        let (address, tcp_queries) = plain_upstream(true).await;
+        let upstream = transport(settings("udp", address), Timeouts::default()).unwrap();
+
+        let answer = upstream.exchange(&query(1)).await.unwrap();
+        assert_eq!((answer[2] & 0x82, answer[12]), (0x80, 1));
+        assert_eq!(tcp_queries.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_upstream() {
+        let (address, tcp_queries) = plain_upstream(false).await;
+        let upstream = transport(settings("tcp", address), Timeouts::default()).unwrap();
+
+        assert_eq!(upstream.exchange(&query(1)).await.unwrap()[12], 1);
+        assert_eq!(upstream.exchange(&query(2)).await.unwrap()[12], 2);
+        assert_eq!(tcp_queries.load(Ordering::SeqCst), 2);
+    }
+}
@@ -29,4 +29,26 @@ if [ ! -e server.crt ]; then
         -extfile extfile.txt -out server.crt
 fi;
 
+# client certificate, used to test mutual TLS
+if [ ! -e client.key ]; then
+    openssl genrsa -out client.key 2048
+fi;
+
+if [ ! -e client.csr ]; then
+    openssl req -new -key client.key -out client.csr \
+        -subj "/C=BR/ST=RJ/L=RJ/O=Test/OU=IT/CN=client.testinternal.local/emailAddress=support@testinternal.local"
+fi;
+
+if [ ! -e client.crt ]; then
+    openssl x509 -req -in client.csr -CA ca.crt -CAkey ca.key \
+        -passin env:CA_PASS -CAcreateserial -days 1500 -sha256 \
+        -extfile client-extfile.txt -out client.crt
+fi;
+
+# the same client certificate and key as PKCS#12, password 12345
+if [ ! -e client.p12 ]; then
+    openssl pkcs12 -export -in client.crt -inkey client.key \
+        -passout env:CA_PASS -out client.p12
+fi;
+
 openssl x509 -in server.crt -text -noout
\ No newline at end of file